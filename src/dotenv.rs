@@ -0,0 +1,302 @@
+//! Dotenv output, the merged variables are written back as `KEY=VALUE` lines
+use crate::parser::parse_env_str;
+use crate::{
+    is_timestamp_comment, merge_env_table, order_section, EnvVar, MergeError, MergeOptions,
+    MergeReport, DEFAULT_MARKER_START,
+};
+use anyhow::Result;
+use std::collections::HashMap;
+use toml::value::Table;
+use toml::Value;
+
+pub(crate) fn merge_existing_dotenv(
+    env_vars: &[EnvVar],
+    file_content: &str,
+    options: &MergeOptions,
+) -> Result<(String, Value, MergeReport)> {
+    let entries = parse_env_str(file_content)
+        .map_err(|e| MergeError::InvalidExisting("dotenv".to_owned(), e.to_string()))?;
+    let mut comments: HashMap<String, Vec<String>> = HashMap::new();
+    let mut table = Table::new();
+    for entry in entries {
+        comments.insert(entry.key.clone(), entry.comments);
+        table.insert(entry.key, Value::String(entry.value));
+    }
+    for var in env_vars.iter().filter(|var| !var.comments.is_empty()) {
+        comments.insert(var.key.clone(), var.comments.clone());
+    }
+
+    // Values are written as real multi-line strings, and keys are never nested or grouped
+    let env_vars: Vec<EnvVar> = env_vars
+        .iter()
+        .map(|var| EnvVar {
+            value: match options.multiline_strings {
+                true => var.value.clone(),
+                false => var.value.replace(&options.multiline_delimiter, "\n"),
+            },
+            ..var.clone()
+        })
+        .collect();
+    let flat = MergeOptions {
+        nested: false,
+        group_by: None,
+        ..options.clone()
+    };
+    let report = merge_env_table(&mut table, &env_vars, &flat)?;
+
+    let mut section = Value::Table(table);
+    order_section(&mut section, &env_vars, &flat);
+    let content = section
+        .as_table()
+        .unwrap()
+        .iter()
+        .map(|(key, value)| {
+            let comments = match options.preserve_comments {
+                true => comments.get(key).map(Vec::as_slice).unwrap_or_default(),
+                false => &[],
+            };
+            let comments: String = comments.iter().map(|line| format!("{}\n", line)).collect();
+            format!("{}{}={}\n", comments, key, format_value(value))
+        })
+        .collect();
+    Ok((content, section, report))
+}
+
+/// Write the section of a TOML document back as `KEY=VALUE` lines. Strings are quoted
+/// and split back into lines, numbers and booleans are left bare so `--typed` reads
+/// them back with the same type. Sub-tables become dotted keys.
+pub(crate) fn extract_dotenv(file_content: &str, options: &MergeOptions) -> Result<String> {
+    let config: Table = toml::from_str(file_content)
+        .map_err(|e| MergeError::InvalidExisting("TOML".to_owned(), e.to_string()))?;
+    let path = options.section_path();
+    let mut section = &config;
+    for (i, key) in path.iter().enumerate() {
+        section = match section.get(*key) {
+            Some(Value::Table(table)) => table,
+            Some(_) => return Err(MergeError::SectionTypeConflict(path[..=i].join(".")).into()),
+            None => return Err(MergeError::SectionNotFound(options.section.clone()).into()),
+        };
+    }
+    let delimiter = written_delimiter(file_content, options);
+    let mut content = String::new();
+    extract_table(section, "", delimiter.as_deref(), &mut content);
+    Ok(content)
+}
+
+/// The delimiter the values were joined with, from the header written with the
+/// markers, falling back to the options for documents without markers
+fn written_delimiter(file_content: &str, options: &MergeOptions) -> Option<String> {
+    let mut lines = file_content.lines();
+    if lines.any(|line| line == options.marker_start || line == DEFAULT_MARKER_START) {
+        return lines
+            .find(|line| !is_timestamp_comment(line))
+            .and_then(|line| line.strip_prefix("# MULTILINE DELIMITER: "))
+            .map(str::to_owned);
+    }
+    match options.multiline_strings {
+        true => None,
+        false => Some(options.multiline_delimiter.clone()),
+    }
+}
+
+fn extract_table(table: &Table, prefix: &str, delimiter: Option<&str>, content: &mut String) {
+    for (key, value) in table {
+        let key = format!("{}{}", prefix, key);
+        let value = match value {
+            Value::Table(table) => {
+                extract_table(table, &format!("{}.", key), delimiter, content);
+                continue;
+            }
+            Value::String(value) => match delimiter {
+                Some(delimiter) => quote(&value.replace(delimiter, "\n")),
+                None => quote(value),
+            },
+            Value::Array(_) => quote(&value.to_string()),
+            Value::Datetime(datetime) => datetime.to_string(),
+            value => value.to_string(),
+        };
+        content.push_str(&format!("{}={}\n", key, value));
+    }
+}
+
+/// Double quote a value, escaping backslashes, quotes and control characters other
+/// than line breaks the way the env parser reads them
+fn quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() && c != '\n' => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Quote a value when the env parser would not read it back as is
+fn format_value(value: &Value) -> String {
+    let value = match value {
+        Value::String(value) => value,
+        Value::Datetime(datetime) => return datetime.to_string(),
+        value => return value.to_string(),
+    };
+    let plain = !value.is_empty()
+        && !value.chars().any(|c| {
+            c.is_whitespace() || c.is_control() || matches!(c, '=' | '#' | '"' | '\'' | '`' | '\\')
+        });
+    match plain {
+        true => value.to_owned(),
+        false => quote(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::parse_env_str;
+    use crate::*;
+
+    #[test]
+    fn test_extract_env_from_toml() {
+        let values = |content: &str| {
+            parse_env_str(content)
+                .unwrap()
+                .into_iter()
+                .map(|entry| (entry.key, entry.value.trim().to_owned()))
+                .collect::<std::collections::BTreeMap<_, _>>()
+        };
+        for (path, options) in [
+            (
+                "src/test_data/typed/typed.env",
+                MergeOptions {
+                    typed: true,
+                    ..Default::default()
+                },
+            ),
+            ("src/test_data/multiline/pem.env", MergeOptions::default()),
+            (
+                "src/test_data/multiline/pem.env",
+                MergeOptions {
+                    multiline_strings: true,
+                    ..Default::default()
+                },
+            ),
+        ] {
+            let patterns = vec![path.to_owned()];
+            let merged = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+            let extracted = extract_env_from_toml(&merged, &MergeOptions::default()).unwrap();
+            let source = std::fs::read_to_string(path).unwrap();
+            assert_eq!(values(&extracted), values(&source));
+            // Merging the extracted file again gives the same document
+            let remerged = merge_env_into_toml_with(&patterns, &merged, &options).unwrap();
+            assert_eq!(remerged, merged);
+        }
+
+        let typed = merge_env_into_toml_with(
+            &["src/test_data/typed/typed.env".to_owned()],
+            "",
+            &MergeOptions {
+                typed: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let extracted = extract_env_from_toml(&typed, &MergeOptions::default()).unwrap();
+        assert!(extracted.contains("PORT=8080\n"));
+        assert!(extracted.contains("QUOTED_PORT=\"8080\"\n"));
+
+        let result = extract_env_from_toml("[other]\n", &MergeOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::SectionNotFound("env".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_dotenv() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            format: OutputFormat::Dotenv,
+            ..Default::default()
+        };
+        let existing = "E=\"a b\"\n";
+        let config_content = merge_env_into_toml_with(&patterns, existing, &options).unwrap();
+        let verify_content =
+            std::fs::read_to_string("src/test_data/dotenv/merged_verify.env").unwrap();
+        assert_eq!(config_content, verify_content);
+
+        // The output reads back to the same values
+        let entries = parse_env_str(&config_content).unwrap();
+        assert_eq!(entries[0].value, "# nice\nasd\nqwe");
+        assert_eq!(entries[3].value, "a b");
+    }
+
+    #[test]
+    fn test_dotenv_round_trip() {
+        let path = "src/test_data/dotenv/escapes.env";
+        let values = |content: &str| {
+            parse_env_str(content)
+                .unwrap()
+                .into_iter()
+                .map(|entry| (entry.key, entry.value))
+                .collect::<std::collections::BTreeMap<_, _>>()
+        };
+        let source = values(&std::fs::read_to_string(path).unwrap());
+        assert_eq!(source["P"], "C:\\new\\");
+        assert_eq!(source["Q"], "say \"hi\" \\n");
+
+        let patterns = vec![path.to_owned()];
+        let options = MergeOptions {
+            format: OutputFormat::Dotenv,
+            ..Default::default()
+        };
+        let dotenv = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        assert!(dotenv.contains("P=\"C:\\\\new\\\\\"\n"));
+        assert_eq!(values(&dotenv), source);
+        // Merging into the written file keeps the values
+        let remerged = merge_env_into_toml_with(&patterns, &dotenv, &options).unwrap();
+        assert_eq!(remerged, dotenv);
+
+        let toml = merge_env_into_toml_with(&patterns, "", &MergeOptions::default()).unwrap();
+        let extracted = extract_env_from_toml(&toml, &MergeOptions::default()).unwrap();
+        assert_eq!(values(&extracted), source);
+
+        // Control characters are escaped like in TOML output
+        let path = "src/test_data/control/control.env";
+        let source = values(&std::fs::read_to_string(path).unwrap());
+        let dotenv = merge_env_into_toml_with(&[path.to_owned()], "", &options).unwrap();
+        assert_eq!(dotenv, "NUL=\"x\\u0000y\"\nPLAIN=ok\nTAB=\"a\\tb\"\n");
+        assert_eq!(values(&dotenv), source);
+    }
+
+    #[test]
+    fn test_merge_env_into_dotenv_preserve_comments() {
+        let patterns = vec!["src/test_data/dotenv/escapes.env".to_owned()];
+        let options = MergeOptions {
+            format: OutputFormat::Dotenv,
+            preserve_comments: true,
+            ..Default::default()
+        };
+        let dotenv = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        assert_eq!(
+            dotenv,
+            "M=\"line 1\nline 2\"\n# Windows paths\nP=\"C:\\\\new\\\\\"\n\
+             # Quotes and a backslash before n\nQ=\"say \\\"hi\\\" \\\\n\"\n"
+        );
+        // Comments of the existing file are kept too
+        let remerged = merge_env_into_toml_with(&patterns, &dotenv, &options).unwrap();
+        assert_eq!(remerged, dotenv);
+
+        let stripped = MergeOptions {
+            preserve_comments: false,
+            ..options
+        };
+        let dotenv = merge_env_into_toml_with(&patterns, "", &stripped).unwrap();
+        assert!(!dotenv.contains('#'));
+    }
+}
@@ -0,0 +1,137 @@
+//! Expansion of `${KEY}` and `$KEY` references between the collected env vars
+use crate::{EnvVar, MergeError, MergeOptions};
+use log::debug;
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Replace the references in the values of `env_vars` with the values they refer to
+pub(crate) fn expand_env_vars(
+    env_vars: &mut [EnvVar],
+    options: &MergeOptions,
+) -> Result<(), MergeError> {
+    let mut expander = Expander {
+        index: env_vars
+            .iter()
+            .enumerate()
+            .map(|(index, var)| (var.key.clone(), index))
+            .collect(),
+        expanded: vec![None; env_vars.len()],
+        stack: Vec::new(),
+        env_vars,
+        options,
+    };
+    for index in 0..expander.env_vars.len() {
+        expander.expand(index)?;
+    }
+    let expanded = expander.expanded;
+    for (var, value) in env_vars.iter_mut().zip(expanded) {
+        var.value = value.unwrap();
+    }
+    Ok(())
+}
+
+struct Expander<'a> {
+    env_vars: &'a [EnvVar],
+    options: &'a MergeOptions,
+    index: HashMap<String, usize>,
+    expanded: Vec<Option<String>>,
+    /// The vars being expanded, to detect cycles
+    stack: Vec<usize>,
+}
+
+impl<'a> Expander<'a> {
+    fn expand(&mut self, index: usize) -> Result<String, MergeError> {
+        if let Some(value) = &self.expanded[index] {
+            return Ok(value.clone());
+        }
+        if let Some(start) = self.stack.iter().position(|&i| i == index) {
+            let chain = self.stack[start..]
+                .iter()
+                .chain([&index])
+                .map(|&i| self.env_vars[i].key.as_str())
+                .collect::<Vec<_>>();
+            return Err(MergeError::CyclicReference(chain.join(" -> ")));
+        }
+        self.stack.push(index);
+        let value = self.substitute(index)?;
+        self.stack.pop();
+        self.expanded[index] = Some(value.clone());
+        Ok(value)
+    }
+
+    fn substitute(&mut self, index: usize) -> Result<String, MergeError> {
+        let var = &self.env_vars[index];
+        let mut chars = var.value.chars().peekable();
+        let mut value = String::new();
+        while let Some(c) = chars.next() {
+            let Some((name, reference)) = (c == '$').then(|| read_reference(&mut chars)).flatten()
+            else {
+                value.push(c);
+                continue;
+            };
+            if let Some(&referenced) = self.index.get(&name) {
+                debug!(
+                    "Expanding {} in {} from {}",
+                    reference, var.key, self.env_vars[referenced].source
+                );
+                value.push_str(&self.expand(referenced)?);
+                continue;
+            }
+            let process_value = match self.options.use_process_env {
+                true => std::env::var(&name).ok(),
+                false => None,
+            };
+            match process_value {
+                Some(process_value) => {
+                    debug!(
+                        "Expanding {} in {} from the process environment",
+                        reference, var.key
+                    );
+                    value.push_str(&process_value);
+                }
+                None if self.options.strict_expand => {
+                    return Err(MergeError::UnknownReference(
+                        name,
+                        var.key.clone(),
+                        var.source.clone(),
+                    ))
+                }
+                None => value.push_str(&reference),
+            }
+        }
+        Ok(value)
+    }
+}
+
+/// Read the name after a `$`, returning it with the reference as written.
+/// Leaves `chars` untouched when there is no reference.
+fn read_reference(chars: &mut Peekable<Chars>) -> Option<(String, String)> {
+    let is_name = |c: &char| c.is_ascii_alphanumeric() || *c == '_';
+    if chars.peek() == Some(&'{') {
+        let mut lookahead = chars.clone();
+        lookahead.next();
+        let mut name = String::new();
+        while let Some(c) = lookahead.next_if(is_name) {
+            name.push(c);
+        }
+        if name.is_empty() || lookahead.next() != Some('}') {
+            return None;
+        }
+        *chars = lookahead;
+        let reference = format!("${{{}}}", name);
+        return Some((name, reference));
+    }
+    if !chars
+        .peek()
+        .is_some_and(|c| c.is_ascii_alphabetic() || *c == '_')
+    {
+        return None;
+    }
+    let mut name = String::new();
+    while let Some(c) = chars.next_if(is_name) {
+        name.push(c);
+    }
+    let reference = format!("${}", name);
+    Some((name, reference))
+}
@@ -0,0 +1,83 @@
+//! JSON output, the section is an object in the top-level object of the document
+use crate::{
+    datetimes_as_strings, merge_env_table, order_section, EnvVar, MergeError, MergeOptions,
+    MergeReport,
+};
+use anyhow::Result;
+use log::{debug, warn};
+use serde_json::{Map, Value};
+
+pub(crate) fn merge_existing_json(
+    env_vars: &[EnvVar],
+    file_content: &str,
+    options: &MergeOptions,
+) -> Result<(String, toml::Value, MergeReport)> {
+    let invalid = |message: String| MergeError::InvalidExisting("JSON".to_owned(), message);
+    let mut document: Value = match file_content.trim().is_empty() {
+        true => Value::Object(Map::new()),
+        false => serde_json::from_str(file_content).map_err(|e| invalid(e.to_string()))?,
+    };
+    let mut object = document
+        .as_object_mut()
+        .ok_or_else(|| invalid("the root is not an object".to_owned()))?;
+
+    let path = options.section_path();
+    let (name, parents) = path.split_last().unwrap();
+    for (i, key) in parents.iter().enumerate() {
+        object = object
+            .entry(key.to_string())
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .ok_or_else(|| MergeError::SectionTypeConflict(path[..=i].join(".")))?;
+    }
+    let section = object.entry(name.to_string()).or_insert_with(|| {
+        debug!("Creating new {:?} object", options.section);
+        Value::Object(Map::new())
+    });
+    if !section.is_object() {
+        if !options.force_section {
+            return Err(MergeError::SectionTypeConflict(options.section.clone()).into());
+        }
+        warn!(
+            "Replacing {:?}: {} with a new object",
+            options.section, section
+        );
+        *section = Value::Object(Map::new());
+    }
+    let mut env_table: toml::Value = toml::Value::try_from(&*section)
+        .map_err(|e| invalid(format!("{} can't be merged: {}", options.section, e)))?;
+
+    let report = merge_env_table(env_table.as_table_mut().unwrap(), env_vars, options)?;
+    order_section(&mut env_table, env_vars, options);
+    *section = serde_json::to_value(datetimes_as_strings(&env_table))?;
+    Ok((
+        serde_json::to_string_pretty(&document)? + "\n",
+        env_table,
+        report,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_merge_env_into_json() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            format: OutputFormat::Json,
+            ..Default::default()
+        };
+        let existing = std::fs::read_to_string("src/test_data/json/old.json").unwrap();
+        let config_content = merge_env_into_toml_with(&patterns, &existing, &options).unwrap();
+        let verify_content = std::fs::read_to_string("src/test_data/json/old_verify.json").unwrap();
+        assert_eq!(config_content, verify_content);
+
+        let result = merge_env_into_toml_with(&patterns, "[env]\n", &options)
+            .err()
+            .unwrap();
+        assert!(result
+            .to_string()
+            .starts_with("Existing content is not valid JSON"));
+    }
+}
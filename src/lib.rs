@@ -0,0 +1,4576 @@
+//! Merge `.env` files into the `[env]` section of a TOML config file.
+mod dotenv;
+mod expand;
+mod json;
+pub mod parser;
+pub mod schema;
+mod toml_file;
+mod yaml;
+
+use glob::{glob, Pattern};
+use ignore::gitignore::Gitignore;
+use log::{debug, info, trace, warn};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use parser::{parse_env_str_with, ParseOptions};
+use rayon::prelude::*;
+use regex::Regex;
+use schema::Schema;
+use serde::Serialize;
+use thiserror::Error;
+use toml::Value;
+
+pub const DEFAULT_SECTION: &str = "env";
+pub const DEFAULT_MULTILINE_DELIMITER: &str = "||||";
+pub const DEFAULT_KEY_PATTERN: &str = "^[A-Za-z_][A-Za-z0-9_]*$";
+pub const DEFAULT_MASK_PATTERN: &str = ".*(SECRET|TOKEN|PASSWORD|KEY).*";
+pub const DEFAULT_MARKER_START: &str = "# GENERATED BY ENV_TO_CONFIG_TOML START";
+pub const DEFAULT_MARKER_END: &str = "# GENERATED BY ENV_TO_CONFIG_TOML END";
+
+#[derive(Error, Debug)]
+pub enum MergeError {
+    #[error("Duplicate key: {0} in {1} and {2}")]
+    DuplicateKey(String, String, String),
+    #[error("No file found for the pattern: {0}")]
+    NoFileFound(String),
+    #[error("Invalid section name: {0:?}, must be bare TOML keys separated by dots")]
+    InvalidSection(String),
+    #[error("Invalid marker: {0:?}, must be a single line TOML comment starting with #")]
+    InvalidMarker(String),
+    #[error("Invalid separator: {0:?}, can't be whitespace, a quote or #")]
+    InvalidSeparator(char),
+    #[error("Invalid multiline delimiter: {0:?}, must be non-empty and on a single line")]
+    InvalidDelimiter(String),
+    #[error("Value of {0} in {1} contains the multiline delimiter {2:?}")]
+    DelimiterInValue(String, String, String),
+    #[error("Value of {0} in {1} contains the control character {2:?}")]
+    ControlCharacter(String, String, char),
+    #[error("Key {0} conflicts with {1}, a key can't be both a value and a table")]
+    KeyConflict(String, String),
+    #[error("The existing TOML document is not a table at its root")]
+    InvalidTomlRoot,
+    #[error("Existing content is not valid {0}: {1}")]
+    InvalidExisting(String, String),
+    #[error("{0} does not match the {1} output format")]
+    FormatMismatch(String, String),
+    #[error("Section {0} must be a table, but the existing document holds a value there")]
+    SectionTypeConflict(String),
+    #[error("Keys {0} and {1} both become {2} after changing their case")]
+    KeyCaseCollision(String, String, String),
+    #[error("Keys {0} and {1} both become {2} after grouping")]
+    GroupCollision(String, String, String),
+    #[error("Cyclic reference between env vars: {0}")]
+    CyclicReference(String),
+    #[error("Unknown reference to {0} in {1} from {2}")]
+    UnknownReference(String, String, String),
+    #[error("Failed to parse {0}: {1}")]
+    ParseFailed(String, parser::ParseError),
+    #[error("Keys {0} and {1} only differ by case, in {2} and {3}")]
+    CaseCollision(String, String, String, String),
+    #[error("Env file not found: {0}")]
+    FileNotFound(String),
+    #[error("{0} is not valid {1}")]
+    InvalidEncoding(String, String),
+    #[error("Reference @{1} of {0} not found in the existing document")]
+    UnresolvedRef(String, String),
+    #[error("Env file not found: {0}, listed in {1} at line {2}")]
+    ManifestFileNotFound(String, String, usize),
+    #[error("Failed to fetch {0}: {1}")]
+    FetchFailed(String, String),
+    #[error("Failed to decompress {0}: {1}")]
+    DecompressFailed(String, String),
+    #[error("Invalid key {0} in {1}")]
+    InvalidKey(String, String),
+    #[error("Missing required keys: {}", .0.join(", "))]
+    MissingRequiredKeys(Vec<String>),
+    #[error("Section {0} not found")]
+    SectionNotFound(String),
+    #[error("Invalid schema {0}: {1}")]
+    InvalidSchema(String, String),
+    #[error("Value of {0} is not a valid {1}: {2:?}")]
+    TypeMismatch(String, String, String),
+    #[error("Invalid glob pattern {0}: {1}")]
+    InvalidPattern(String, #[source] glob::PatternError),
+    #[error("{0} has no env vars")]
+    EmptyFile(String),
+    /// Every duplicate key with its sources in merge order, sorted by key
+    #[error("Duplicate keys:{}", format_duplicates(.0))]
+    DuplicateKeys(Vec<(String, Vec<String>)>),
+    #[error("Keys of the section no longer in the env files: {}", .0.join(", "))]
+    SectionShrinks(Vec<String>),
+}
+
+/// What to do when a key is defined in more than one env file
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Fail with a [`MergeError::DuplicateKey`]
+    #[default]
+    Error,
+    /// Keep the value of the first file, in merge order
+    FirstWins,
+    /// Keep the value of the last file, in merge order
+    LastWins,
+}
+
+/// What to do with control characters in values, other than line breaks
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ControlChars {
+    /// Merge them as is, TOML and dotenv output write them escaped like `\t`
+    #[default]
+    Keep,
+    /// Log a warning for each value holding one
+    Warn,
+    /// Fail with a [`MergeError::ControlCharacter`]
+    Error,
+}
+
+/// How a merged value combines with the value already at its key
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Replace the existing value as a whole
+    #[default]
+    ReplaceSection,
+    /// Merge tables recursively, the merged values win over existing scalars.
+    /// A table and a value at the same key fail with [`MergeError::KeyConflict`]
+    Deep,
+}
+
+/// How the merged document ends
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FinalNewline {
+    /// With exactly one newline
+    #[default]
+    Ensure,
+    /// Without any newline
+    Trim,
+    /// As written, blank lines of the existing document included
+    Keep,
+}
+
+impl FinalNewline {
+    pub fn apply(&self, content: String) -> String {
+        match self {
+            FinalNewline::Ensure => format!("{}\n", content.trim_end_matches('\n')),
+            FinalNewline::Trim => content.trim_end_matches('\n').to_owned(),
+            FinalNewline::Keep => content,
+        }
+    }
+}
+
+/// The order of the keys in the written section
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputSort {
+    /// By name
+    Alpha,
+    /// In the order the env files define them, keys of the existing section that
+    /// aren't merged last
+    Insertion,
+    /// Existing keys keep their place, new keys are appended in the order the env
+    /// files define them
+    #[value(name = "none")]
+    Unsorted,
+}
+
+/// The order env files are merged in
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortBy {
+    /// By path, case-insensitively
+    #[default]
+    Name,
+    /// By modification time, oldest first
+    Mtime,
+}
+
+/// How to change the case of keys or values
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyCase {
+    #[default]
+    Keep,
+    Upper,
+    Lower,
+}
+
+impl KeyCase {
+    pub fn apply(&self, key: &str) -> String {
+        match self {
+            KeyCase::Keep => key.to_owned(),
+            KeyCase::Upper => key.to_uppercase(),
+            KeyCase::Lower => key.to_lowercase(),
+        }
+    }
+}
+
+/// The encoding env files are read in, the output is always UTF-8
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputEncoding {
+    #[default]
+    #[value(name = "utf-8")]
+    Utf8,
+    /// ISO-8859-1, decoded as its Windows-1252 superset
+    Latin1,
+    /// Little endian unless the file starts with a big endian byte order mark
+    #[value(name = "utf-16")]
+    Utf16,
+}
+
+impl InputEncoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InputEncoding::Utf8 => "utf-8",
+            InputEncoding::Latin1 => "latin1",
+            InputEncoding::Utf16 => "utf-16",
+        }
+    }
+
+    /// Decode the content of an env file, `None` if it isn't valid in this encoding
+    pub fn decode(&self, bytes: Vec<u8>) -> Option<String> {
+        let encoding = match self {
+            InputEncoding::Utf8 => return String::from_utf8(bytes).ok(),
+            InputEncoding::Latin1 => encoding_rs::WINDOWS_1252,
+            InputEncoding::Utf16 => encoding_rs::UTF_16LE,
+        };
+        let (content, _, had_errors) = encoding.decode(&bytes);
+        (!had_errors).then(|| content.into_owned())
+    }
+}
+
+/// The format of the merged document
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Toml,
+    Json,
+    Yaml,
+    Dotenv,
+}
+
+impl OutputFormat {
+    /// The file extensions used for this format
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            OutputFormat::Toml => &["toml"],
+            OutputFormat::Json => &["json"],
+            OutputFormat::Yaml => &["yaml", "yml"],
+            OutputFormat::Dotenv => &["env"],
+        }
+    }
+
+    /// The format a file extension belongs to, if any
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        [
+            OutputFormat::Toml,
+            OutputFormat::Json,
+            OutputFormat::Yaml,
+            OutputFormat::Dotenv,
+        ]
+        .into_iter()
+        .find(|format| format.extensions().contains(&extension))
+    }
+}
+
+/// Options controlling how the env files are merged into the TOML document
+#[derive(clap::Args, Debug, Clone)]
+pub struct MergeOptions {
+    /// The TOML section the env vars are written to
+    #[arg(long, default_value = DEFAULT_SECTION)]
+    pub section: String,
+
+    /// Glob pattern of files to skip even if matched by a pattern, may be repeated
+    #[arg(long)]
+    pub exclude: Vec<String>,
+
+    /// The delimiter multi-line values are joined with
+    #[arg(long, default_value = DEFAULT_MULTILINE_DELIMITER)]
+    pub multiline_delimiter: String,
+
+    /// Keep multi-line values as TOML multi-line strings instead of joining them
+    #[arg(long, conflicts_with = "multiline_delimiter")]
+    pub multiline_strings: bool,
+
+    /// Write unquoted integer, float, boolean and RFC 3339 date and time values as
+    /// native TOML types. Numbers with a `+` sign or leading zeros, like `007`, stay
+    /// strings
+    #[arg(long)]
+    pub typed: bool,
+
+    /// Write unquoted values containing this delimiter as arrays of strings, e.g.
+    /// `PORTS=80,443` with `,`. Empty elements are dropped, with `--typed` the
+    /// types of the elements are inferred
+    #[arg(long)]
+    pub array_delimiter: Option<String>,
+
+    /// Write values holding a JSON object or array as TOML tables and arrays, their
+    /// numbers, booleans and strings keep the same TOML types. TOML has no null, null
+    /// members and elements are dropped. Other values are left to `--typed`. Values are
+    /// parsed after `--expand` and `--value-case`, unquoted ones starting with `{` or
+    /// `[` are read up to the end of the line
+    #[arg(long)]
+    pub parse_json: bool,
+
+    /// Split keys on `.` into nested tables, e.g. `DATABASE.HOST` to `[env.DATABASE]`
+    #[arg(long)]
+    pub nested: bool,
+
+    /// Group keys into sub-tables on the first occurrence of this separator, e.g.
+    /// `DB_HOST` to `[env.DB]` with `_`. Keys starting or ending with it stay at the
+    /// section root
+    #[arg(long)]
+    pub group_by: Option<String>,
+
+    /// How to handle a key defined in more than one env file
+    #[arg(long, value_enum, default_value_t)]
+    pub on_duplicate: DuplicatePolicy,
+
+    /// With `--on-duplicate error`, report every duplicate key of the env files at once
+    /// with [`MergeError::DuplicateKeys`] instead of failing on the first one
+    #[arg(long)]
+    pub report_all_duplicates: bool,
+
+    /// Replace unquoted values like `@server.host` with the value at this dotted path
+    /// of the existing document, keeping its type. TOML output only
+    #[arg(long)]
+    pub resolve_refs: bool,
+
+    /// Sort the keys and tables of the whole document alphabetically, rewriting the
+    /// other sections. The keys of the section keep the `--output-sort`. TOML output only
+    #[arg(long)]
+    pub sort_tables: bool,
+
+    /// Leave out the section and its markers, removing the existing ones, when no key
+    /// is left in it, like after filtering out every key. TOML output only
+    #[arg(long)]
+    pub prune_empty_section: bool,
+
+    /// Replace an existing non-table value at the section with a new table
+    #[arg(long)]
+    pub force_section: bool,
+
+    /// Fail with [`MergeError::SectionShrinks`] when keys of the existing section are
+    /// no longer defined by the env files, like after deleting one of them
+    #[arg(long)]
+    pub no_shrink: bool,
+
+    /// Remove the keys of the existing section that the env files no longer define,
+    /// so that the section only holds the merged keys
+    #[arg(long, conflicts_with = "no_shrink")]
+    pub replace_env: bool,
+
+    /// How the merged document ends
+    #[arg(long, value_enum, default_value_t)]
+    pub final_newline: FinalNewline,
+
+    /// How merged values combine with the values already in the section
+    #[arg(long, value_enum, default_value_t)]
+    pub merge_strategy: MergeStrategy,
+
+    /// Keep files in glob order and keys in file order instead of sorting them.
+    /// Duplicate detection still applies
+    #[arg(long)]
+    pub preserve_order: bool,
+
+    /// The order of the keys in the section, independent of the order env files are
+    /// merged in. Defaults to `alpha`, or `none` with `--preserve-order`
+    #[arg(long, value_enum)]
+    pub output_sort: Option<OutputSort>,
+
+    /// Sort keys by their raw bytes instead of case-insensitively
+    #[arg(long)]
+    pub case_sensitive_sort: bool,
+
+    /// The format of the merged document. Markers are only written for TOML
+    #[arg(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+
+    /// Write the comment lines above the variables of the env files into dotenv output
+    #[arg(long)]
+    pub preserve_comments: bool,
+
+    /// Only merge the keys starting with this prefix
+    #[arg(long)]
+    pub prefix_filter: Option<String>,
+
+    /// Remove this prefix from the keys, after `--prefix-filter`
+    #[arg(long)]
+    pub strip_prefix: Option<String>,
+
+    /// Merge the key OLD as NEW, may be repeated. Applied after `--strip-prefix`
+    #[arg(long = "rename", value_name = "OLD=NEW", value_parser = parse_rename)]
+    pub renames: Vec<(String, String)>,
+
+    /// Write a comment with the generation time and the version of the tool after
+    /// the START marker of TOML output. `--check` ignores it
+    #[arg(long)]
+    pub timestamp: bool,
+
+    /// Change the case of the keys, after `--strip-prefix`
+    #[arg(long, value_enum, default_value_t)]
+    pub key_case: KeyCase,
+
+    /// Change the case of the values, before `--typed` infers their types. With
+    /// `--no-trim`, quoted and multi-line values are kept as written
+    #[arg(long, value_enum, default_value_t)]
+    pub value_case: KeyCase,
+
+    /// Prefix the keys of each env file with the name of its directory, so that
+    /// `services/auth/db.env` gives `AUTH_<KEY>`. Applied before duplicates and keys are checked
+    #[arg(long)]
+    pub namespace_by_dir: bool,
+
+    /// The separator between the directory name and the key with `--namespace-by-dir`
+    #[arg(long, default_value = "_")]
+    pub namespace_separator: String,
+
+    /// The case of the directory name with `--namespace-by-dir`
+    #[arg(long, value_enum, default_value_t = KeyCase::Upper)]
+    pub namespace_case: KeyCase,
+
+    /// Replace `${KEY}` and `$KEY` in values with the value of another env var
+    #[arg(long)]
+    pub expand: bool,
+
+    /// Fail on references to unknown env vars instead of leaving them as is
+    #[arg(long, requires = "expand")]
+    pub strict_expand: bool,
+
+    /// Fall back to the process environment for references to unknown env vars
+    #[arg(long, requires = "expand")]
+    pub use_process_env: bool,
+
+    /// Don't wrap the section in the START/END marker comments. Markers of previous
+    /// runs are removed
+    #[arg(long)]
+    pub no_markers: bool,
+
+    /// The comment line written before the section
+    #[arg(long, default_value = DEFAULT_MARKER_START)]
+    pub marker_start: String,
+
+    /// The comment line written after the section
+    #[arg(long, default_value = DEFAULT_MARKER_END)]
+    pub marker_end: String,
+
+    /// Add a comment with the source file after each generated key
+    #[arg(long)]
+    pub annotate_source: bool,
+
+    /// The separator between keys and values in the env files, only its first
+    /// occurrence on a line splits
+    #[arg(long, default_value_t = '=')]
+    pub separator: char,
+
+    /// Fail on lines with a key and no separator instead of reading them as empty values
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Cut unquoted values at a `#` after whitespace, like `PORT=8080 # default port`.
+    /// Without it the comment is part of the value
+    #[arg(long)]
+    pub strip_inline_comments: bool,
+
+    /// What to do with values holding control characters other than line breaks
+    #[arg(long, value_enum, default_value_t)]
+    pub control_chars: ControlChars,
+
+    /// The encoding of the env files and stdin, `--merge-toml` files and URLs are
+    /// always read as UTF-8
+    #[arg(long, value_enum, default_value_t)]
+    pub input_encoding: InputEncoding,
+
+    /// Keep leading and trailing whitespace of values, which are trimmed otherwise
+    #[arg(long)]
+    pub no_trim: bool,
+
+    /// Fail with [`MergeError::EmptyFile`] on env files without any variable,
+    /// instead of warning
+    #[arg(long)]
+    pub error_on_empty: bool,
+
+    /// Fail on keys not matching `--key-pattern`, before they are filtered or renamed
+    #[arg(long)]
+    pub validate_keys: bool,
+
+    /// Regex the keys must match with `--validate-keys`
+    #[arg(long, default_value = DEFAULT_KEY_PATTERN, value_parser = Regex::new)]
+    pub key_pattern: Regex,
+
+    /// Fail when two keys only differ by case, like `Path` and `PATH`
+    #[arg(long)]
+    pub detect_case_collisions: bool,
+
+    /// Skip matched files nested more than this many directories below the
+    /// part of their pattern without wildcards
+    #[arg(long)]
+    pub max_depth: Option<usize>,
+
+    /// Skip matched files reached through symlinks below the part of their pattern
+    /// without wildcards. They are read by default, which `--follow-symlinks` used to
+    /// turn on, and files seen again through a symlink cycle only once
+    #[arg(long)]
+    pub no_follow_symlinks: bool,
+
+    /// Deprecated, symlinks are followed by default
+    #[arg(long, hide = true, conflicts_with = "no_follow_symlinks")]
+    pub follow_symlinks: bool,
+
+    /// Only merge the matched files of this profile, named like `app.NAME.env`, and the
+    /// base files without a profile, like `app.env`. Base files are merged first, so
+    /// that the profile overrides them with `--on-duplicate last-wins`; with the
+    /// default policy a key set to different values in both fails
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// Skip matched files ignored by the `.gitignore` files of their directory and
+    /// the directories above, up to the root of the git repository
+    #[arg(long)]
+    pub respect_gitignore: bool,
+
+    /// Log the env files in the order they are merged, with the number of keys each
+    /// one contributes
+    #[arg(long)]
+    pub trace_sources: bool,
+
+    /// The order env files are merged in, which decides the winner with
+    /// `--on-duplicate last-wins`
+    #[arg(long, value_enum, default_value_t, conflicts_with = "preserve_order")]
+    pub sort_by: SortBy,
+
+    /// Env files to merge in exactly this order, instead of globbing the patterns
+    #[arg(long, num_args = 1.., conflicts_with = "pattern")]
+    pub files: Vec<PathBuf>,
+
+    /// File listing the env files to merge one per line, in that order, instead of
+    /// globbing the patterns. Paths are relative to its directory, `#` starts a comment
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["pattern", "files"])]
+    pub manifest: Option<PathBuf>,
+
+    /// Fail unless this key is merged, may be repeated. Checked against the keys as
+    /// written, after `--strip-prefix` and `--key-case`
+    #[arg(long)]
+    pub require: Vec<String>,
+
+    /// TOML file, or JSON file for the `.json` extension, of the types of keys, like
+    /// `PORT = "int"`. Types are `int`, `float`, `bool`, `string` and `array<string>`,
+    /// other keys stay strings, even with `--typed`
+    #[arg(long, value_name = "PATH", value_parser = parse_schema)]
+    pub schema: Option<Schema>,
+
+    /// Merge `KEY=VALUE` when the env files don't define KEY, may be repeated.
+    /// Defaults count for `--require`
+    #[arg(long = "default", value_name = "KEY=VALUE", value_parser = parse_default)]
+    pub defaults: Vec<(String, String)>,
+
+    /// Env file read before the others, like `.env.defaults`, whose keys any other
+    /// file may override without a duplicate error
+    #[arg(long, value_name = "PATH")]
+    pub defaults_file: Option<PathBuf>,
+
+    /// Only merge the keys defined in this env file, its values are ignored. Keys are
+    /// matched as written in the env files, before `--strip-prefix` and `--rename`
+    #[arg(long, value_name = "PATH")]
+    pub allow_keys_from: Option<PathBuf>,
+
+    /// TOML file whose section is merged like another env file, after the env
+    /// files, may be repeated. Values keep their TOML types
+    #[arg(long)]
+    pub merge_toml: Vec<PathBuf>,
+
+    /// Log `***` instead of the values of keys matching `--mask-pattern`. The
+    /// output is not masked
+    #[arg(long)]
+    pub mask: bool,
+
+    /// Regex of the keys whose values are masked in logs with `--mask`
+    #[arg(long, default_value = DEFAULT_MASK_PATTERN, value_parser = Regex::new)]
+    pub mask_pattern: Regex,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        Self {
+            section: DEFAULT_SECTION.to_owned(),
+            exclude: Vec::new(),
+            multiline_delimiter: DEFAULT_MULTILINE_DELIMITER.to_owned(),
+            multiline_strings: false,
+            typed: false,
+            array_delimiter: None,
+            parse_json: false,
+            nested: false,
+            group_by: None,
+            on_duplicate: DuplicatePolicy::Error,
+            report_all_duplicates: false,
+            resolve_refs: false,
+            sort_tables: false,
+            prune_empty_section: false,
+            force_section: false,
+            no_shrink: false,
+            replace_env: false,
+            final_newline: FinalNewline::Ensure,
+            merge_strategy: MergeStrategy::ReplaceSection,
+            preserve_order: false,
+            output_sort: None,
+            case_sensitive_sort: false,
+            format: OutputFormat::Toml,
+            preserve_comments: false,
+            prefix_filter: None,
+            strip_prefix: None,
+            renames: Vec::new(),
+            timestamp: false,
+            key_case: KeyCase::Keep,
+            value_case: KeyCase::Keep,
+            namespace_by_dir: false,
+            namespace_separator: "_".to_owned(),
+            namespace_case: KeyCase::Upper,
+            expand: false,
+            strict_expand: false,
+            use_process_env: false,
+            no_markers: false,
+            marker_start: DEFAULT_MARKER_START.to_owned(),
+            marker_end: DEFAULT_MARKER_END.to_owned(),
+            annotate_source: false,
+            separator: '=',
+            strict: false,
+            strip_inline_comments: false,
+            control_chars: ControlChars::Keep,
+            input_encoding: InputEncoding::Utf8,
+            no_trim: false,
+            error_on_empty: false,
+            validate_keys: false,
+            key_pattern: Regex::new(DEFAULT_KEY_PATTERN).unwrap(),
+            detect_case_collisions: false,
+            max_depth: None,
+            no_follow_symlinks: false,
+            follow_symlinks: false,
+            profile: None,
+            respect_gitignore: false,
+            trace_sources: false,
+            sort_by: SortBy::Name,
+            files: Vec::new(),
+            manifest: None,
+            require: Vec::new(),
+            schema: None,
+            defaults: Vec::new(),
+            defaults_file: None,
+            allow_keys_from: None,
+            merge_toml: Vec::new(),
+            mask: false,
+            mask_pattern: Regex::new(DEFAULT_MASK_PATTERN).unwrap(),
+        }
+    }
+}
+
+impl MergeOptions {
+    pub fn validate(&self) -> Result<(), MergeError> {
+        if !self.section_path().into_iter().all(is_bare_key) {
+            return Err(MergeError::InvalidSection(self.section.clone()));
+        }
+        if self.multiline_delimiter.is_empty() || self.multiline_delimiter.contains('\n') {
+            return Err(MergeError::InvalidDelimiter(
+                self.multiline_delimiter.clone(),
+            ));
+        }
+        if self.separator.is_whitespace() || matches!(self.separator, '#' | '"' | '\'' | '`') {
+            return Err(MergeError::InvalidSeparator(self.separator));
+        }
+        for marker in [&self.marker_start, &self.marker_end] {
+            if !marker.starts_with('#') || marker.contains('\n') {
+                return Err(MergeError::InvalidMarker(marker.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The value of `key` as it should appear in logs, `***` when masked
+    pub fn log_value<'a>(&self, key: &str, value: &'a str) -> &'a str {
+        match self.mask && self.mask_pattern.is_match(key) {
+            true => "***",
+            false => value,
+        }
+    }
+
+    /// The order of the keys in the section, after `--preserve-order`
+    pub fn key_order(&self) -> OutputSort {
+        match (self.output_sort, self.preserve_order) {
+            (Some(output_sort), _) => output_sort,
+            (None, true) => OutputSort::Unsorted,
+            (None, false) => OutputSort::Alpha,
+        }
+    }
+
+    /// The keys of the tables leading to the section, `app.runtime.env` is nested
+    /// in `app` and `runtime`
+    pub(crate) fn section_path(&self) -> Vec<&str> {
+        self.section.split('.').collect()
+    }
+}
+
+fn parse_default(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected KEY=VALUE, got {:?}", arg)),
+    }
+}
+
+fn parse_rename(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+            Ok((old.to_owned(), new.to_owned()))
+        }
+        _ => Err(format!("expected OLD=NEW, got {:?}", arg)),
+    }
+}
+
+fn parse_schema(arg: &str) -> Result<Schema, MergeError> {
+    Schema::from_path(Path::new(arg))
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Merge the `.env` files matched by `patterns` into the TOML document `existing`
+/// and return the resulting document.
+///
+/// `existing` may be empty, in which case a new document is created.
+pub fn merge_env_into_toml(patterns: &[String], existing: &str) -> Result<String> {
+    merge_env_into_toml_with(patterns, existing, &MergeOptions::default())
+}
+
+/// Same as [`merge_env_into_toml`], with explicit [`MergeOptions`].
+pub fn merge_env_into_toml_with(
+    patterns: &[String],
+    existing: &str,
+    options: &MergeOptions,
+) -> Result<String> {
+    let (content, _) = merge_env_into_toml_with_summary(patterns, existing, options)?;
+    Ok(content)
+}
+
+/// Same as [`merge_env_into_toml_with`], also returning a [`MergeSummary`].
+pub fn merge_env_into_toml_with_summary(
+    patterns: &[String],
+    existing: &str,
+    options: &MergeOptions,
+) -> Result<(String, MergeSummary)> {
+    let (content, report) = merge_env_into_toml_with_report(patterns, existing, options)?;
+    Ok((content, report.summary()))
+}
+
+/// Same as [`merge_env_into_toml_with`], also returning a [`MergeReport`] of the
+/// changed keys.
+pub fn merge_env_into_toml_with_report(
+    patterns: &[String],
+    existing: &str,
+    options: &MergeOptions,
+) -> Result<(String, MergeReport)> {
+    let outcome = merge(patterns, existing, options)?;
+    Ok((outcome.content, outcome.report))
+}
+
+/// Merge the `.env` files matched by `patterns` into `existing` like
+/// [`merge_env_into_toml_with`], returning everything known about the merge.
+pub fn merge(patterns: &[String], existing: &str, options: &MergeOptions) -> Result<MergeOutcome> {
+    options.validate()?;
+    let mut env_vars = select_env_vars(get_env_vars(patterns, options)?, options)?;
+    apply_defaults(&mut env_vars, options);
+    check_required_keys(&env_vars, options)?;
+    let (content, section, report) = merge_existing(&env_vars, existing, options)?;
+    let content = options.final_newline.apply(content);
+    let sources = env_vars
+        .into_iter()
+        .map(|var| (key_path(&var.key, options).join("."), var.source))
+        .collect();
+    Ok(MergeOutcome {
+        content,
+        section,
+        sources,
+        report,
+    })
+}
+
+/// The keys the env files define with their source, sorted by key, after the key
+/// filter, prefix stripping, renames and key case. Duplicates fail like for [`merge`]
+pub fn list_keys(patterns: &[String], options: &MergeOptions) -> Result<Vec<(String, String)>> {
+    options.validate()?;
+    let env_vars = select_env_vars(get_env_vars(patterns, options)?, options)?;
+    let mut keys: Vec<(String, String)> = env_vars
+        .into_iter()
+        .map(|var| (var.key, var.source))
+        .collect();
+    keys.sort();
+    Ok(keys)
+}
+
+/// Write the section of a TOML document back as `.env` content, the inverse of
+/// [`merge_env_into_toml_with`].
+pub fn extract_env_from_toml(existing: &str, options: &MergeOptions) -> Result<String> {
+    options.validate()?;
+    dotenv::extract_dotenv(existing, options)
+}
+
+/// The result of [`merge`].
+///
+/// This type is part of the stable API: fields are only added in minor releases,
+/// which is why it can't be constructed outside of this crate.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct MergeOutcome {
+    /// The merged document, in the output format, as it would be written
+    pub content: String,
+    /// The merged section, sorted like in `content`
+    pub section: Value,
+    /// The source of each merged key, by its dotted path in the section. Sources
+    /// are file paths, URLs, `<stdin>` or `<default>`
+    pub sources: HashMap<String, String>,
+    /// How the merge changed the section
+    pub report: MergeReport,
+}
+
+/// How the merged env vars changed the section
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeSummary {
+    /// Keys not present in the section before
+    pub added: usize,
+    /// Keys present with a different value
+    pub updated: usize,
+    /// Keys present with the same value
+    pub unchanged: usize,
+}
+
+impl MergeSummary {
+    pub fn total(&self) -> usize {
+        self.added + self.updated + self.unchanged
+    }
+}
+
+impl std::fmt::Display for MergeSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Merged {} env vars: {} added, {} updated, {} unchanged",
+            self.total(),
+            self.added,
+            self.updated,
+            self.unchanged
+        )
+    }
+}
+
+/// The keys the merged env vars changed in the section, with their values
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MergeReport {
+    /// Keys not present in the section before
+    pub added: Vec<AddedKey>,
+    /// Keys present with a different value
+    pub updated: Vec<UpdatedKey>,
+    /// Keys present with the same value
+    pub unchanged: Vec<String>,
+    /// Keys of the section no longer in the env files, removed by `--replace-env`
+    pub removed: Vec<RemovedKey>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AddedKey {
+    pub key: String,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RemovedKey {
+    pub key: String,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UpdatedKey {
+    pub key: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+impl MergeReport {
+    pub fn summary(&self) -> MergeSummary {
+        MergeSummary {
+            added: self.added.len(),
+            updated: self.updated.len(),
+            unchanged: self.unchanged.len(),
+        }
+    }
+}
+
+/// The `--timestamp` comment written after the START marker
+pub(crate) fn timestamp_comment(time: SystemTime) -> String {
+    format!(
+        "# Generated {} by env_to_config_toml {}",
+        humantime::format_rfc3339_seconds(time),
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+pub(crate) fn is_timestamp_comment(line: &str) -> bool {
+    line.starts_with("# Generated ") && line.trim_end().contains(" by env_to_config_toml ")
+}
+
+/// `content` without the `--timestamp` comment, to compare outputs generated at
+/// different times
+pub fn without_timestamp(content: &str) -> String {
+    content
+        .split_inclusive('\n')
+        .filter(|line| !is_timestamp_comment(line))
+        .collect()
+}
+
+/// The pattern that reads env content from stdin
+pub const STDIN_PATTERN: &str = "-";
+
+/// A variable collected from the env files
+#[derive(Debug, Clone)]
+struct EnvVar {
+    key: String,
+    value: String,
+    /// The value as written in the env file
+    raw_value: String,
+    quoted: bool,
+    /// The file the variable was read from, `<stdin>` for stdin
+    source: String,
+    /// The value read from a `--merge-toml` file when it isn't a string, written as is
+    typed_value: Option<Value>,
+    /// The comment lines above the variable in the env file
+    comments: Vec<String>,
+}
+
+/// Where env content is read from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum EnvSource {
+    File(PathBuf),
+    Url(String),
+    Stdin,
+    /// The section of a TOML file, from `--merge-toml`
+    Toml(PathBuf),
+    /// The `--defaults-file`, read before the other sources
+    Defaults(PathBuf),
+}
+
+impl EnvSource {
+    fn read(&self, encoding: InputEncoding) -> Result<String> {
+        let context = || format!("Failed to read env file {}", self);
+        let bytes = match self {
+            EnvSource::File(path) if path.extension().is_some_and(|ext| ext == "gz") => {
+                read_gzip(path)?
+            }
+            EnvSource::File(path) | EnvSource::Defaults(path) => {
+                std::fs::read(path).with_context(context)?
+            }
+            EnvSource::Toml(path) => return read_to_string(path).with_context(context),
+            EnvSource::Url(url) => return Ok(fetch(url)?),
+            EnvSource::Stdin => {
+                let mut bytes = Vec::new();
+                std::io::Read::read_to_end(&mut std::io::stdin(), &mut bytes)
+                    .with_context(context)?;
+                bytes
+            }
+        };
+        encoding
+            .decode(bytes)
+            .ok_or_else(|| MergeError::InvalidEncoding(self.to_string(), encoding.name().into()))
+            .map_err(Into::into)
+    }
+}
+
+/// Whether a pattern or file is an `http://` or `https://` URL, fetched instead of globbed
+pub fn is_url(pattern: &str) -> bool {
+    pattern.starts_with("http://") || pattern.starts_with("https://")
+}
+
+#[cfg(feature = "gzip")]
+fn read_gzip(path: &Path) -> Result<Vec<u8>, MergeError> {
+    use std::io::Read;
+    let failed =
+        |message: String| MergeError::DecompressFailed(path.display().to_string(), message);
+    let file = std::fs::File::open(path).map_err(|e| failed(e.to_string()))?;
+    let mut content = Vec::new();
+    flate2::read::GzDecoder::new(file)
+        .read_to_end(&mut content)
+        .map_err(|e| failed(e.to_string()))?;
+    Ok(content)
+}
+
+#[cfg(not(feature = "gzip"))]
+fn read_gzip(path: &Path) -> Result<Vec<u8>, MergeError> {
+    Err(MergeError::DecompressFailed(
+        path.display().to_string(),
+        "built without the gzip feature".to_owned(),
+    ))
+}
+
+#[cfg(feature = "http")]
+fn fetch(url: &str) -> Result<String, MergeError> {
+    let failed = |message: String| MergeError::FetchFailed(url.to_owned(), message);
+    ureq::get(url)
+        .call()
+        .map_err(|e| failed(e.to_string()))?
+        .into_string()
+        .map_err(|e| failed(e.to_string()))
+}
+
+#[cfg(not(feature = "http"))]
+fn fetch(url: &str) -> Result<String, MergeError> {
+    Err(MergeError::FetchFailed(
+        url.to_owned(),
+        "built without the http feature".to_owned(),
+    ))
+}
+
+impl std::fmt::Display for EnvSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvSource::File(path) | EnvSource::Toml(path) | EnvSource::Defaults(path) => {
+                write!(f, "{}", path.display())
+            }
+            EnvSource::Url(url) => write!(f, "{}", url),
+            EnvSource::Stdin => write!(f, "<stdin>"),
+        }
+    }
+}
+
+fn get_env_sources(patterns: &[String], options: &MergeOptions) -> Result<Vec<EnvSource>> {
+    if let Some(manifest) = &options.manifest {
+        return manifest_sources(manifest);
+    }
+    if !options.files.is_empty() {
+        let mut seen = HashSet::new();
+        let mut sources = Vec::new();
+        for path in &options.files {
+            if let Some(url) = path.to_str().filter(|path| is_url(path)) {
+                if seen.insert(path) {
+                    sources.push(EnvSource::Url(url.to_owned()));
+                }
+                continue;
+            }
+            if !path.is_file() {
+                return Err(MergeError::FileNotFound(path.display().to_string()).into());
+            }
+            if seen.insert(path) {
+                sources.push(EnvSource::File(path.clone()));
+            }
+        }
+        return Ok(sources);
+    }
+    let excludes = options
+        .exclude
+        .iter()
+        .map(|exclude| {
+            Pattern::new(exclude).map_err(|e| MergeError::InvalidPattern(exclude.clone(), e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut env_paths: Vec<PathBuf> = Vec::new();
+    let mut canonical_paths = HashSet::new();
+    let urls: Vec<&String> = patterns.iter().filter(|pattern| is_url(pattern)).collect();
+    for pattern in patterns
+        .iter()
+        .filter(|pattern| *pattern != STDIN_PATTERN && !is_url(pattern))
+    {
+        let base = pattern_base(pattern);
+        env_paths.extend(
+            glob(pattern)
+                .map_err(|e| MergeError::InvalidPattern(pattern.clone(), e))?
+                .filter_map(Result::ok)
+                .filter(|path| path.is_file())
+                .filter(|path| match options.max_depth {
+                    Some(max_depth) if depth_below(path, &base) > max_depth => {
+                        debug!("Skipping env file deeper than {}: {:?}", max_depth, path);
+                        false
+                    }
+                    _ => true,
+                })
+                .filter(|path| match options.no_follow_symlinks {
+                    false => match std::fs::canonicalize(path) {
+                        Ok(canonical) => canonical_paths.insert(canonical),
+                        Err(_) => false,
+                    },
+                    true if through_symlink(path, &base) => {
+                        debug!("Skipping env file behind a symlink: {:?}", path);
+                        false
+                    }
+                    true => true,
+                }),
+        );
+    }
+    env_paths.retain(|path| {
+        let path_str = path.to_string_lossy();
+        let excluded = excludes.iter().any(|exclude| exclude.matches(&path_str));
+        if excluded {
+            debug!("Excluding env file: {:?}", path);
+        }
+        !excluded
+    });
+    if options.respect_gitignore {
+        let mut gitignores = HashMap::new();
+        env_paths.retain(|path| {
+            let ignored = is_gitignored(path, &mut gitignores);
+            if ignored {
+                debug!("Skipping gitignored env file: {:?}", path);
+            }
+            !ignored
+        });
+    }
+    let stdin = patterns.iter().any(|pattern| pattern == STDIN_PATTERN);
+    if env_paths.is_empty() && urls.is_empty() && !stdin {
+        return Err(MergeError::NoFileFound(patterns.join(", ")).into());
+    }
+
+    if !options.preserve_order {
+        env_paths.sort_by_cached_key(|path| path_sort_key(path));
+        if options.sort_by == SortBy::Mtime {
+            sort_by_mtime(&mut env_paths);
+        }
+    }
+    if let Some(profile) = &options.profile {
+        env_paths.retain(|path| {
+            let profiles = file_profiles(path);
+            let kept = profiles.is_empty() || profiles.contains(&profile.as_str());
+            if !kept {
+                debug!("Skipping env file of another profile: {:?}", path);
+            }
+            kept
+        });
+        // The sort is stable, files keep their order within the base and the profile
+        env_paths.sort_by_key(|path| !file_profiles(path).is_empty());
+    }
+    let mut seen = HashSet::new();
+    env_paths.retain(|path| seen.insert(path.clone()));
+    let mut sources: Vec<_> = env_paths.into_iter().map(EnvSource::File).collect();
+    let mut seen = HashSet::new();
+    for url in urls.into_iter().filter(|url| seen.insert(*url)) {
+        sources.push(EnvSource::Url(url.clone()));
+    }
+    if stdin {
+        sources.push(EnvSource::Stdin);
+    }
+    Ok(sources)
+}
+
+/// The env files listed in the `--manifest`, in order
+fn manifest_sources(manifest: &Path) -> Result<Vec<EnvSource>> {
+    let content = read_to_string(manifest)
+        .with_context(|| format!("Failed to read manifest {}", manifest.display()))?;
+    let dir = manifest.parent().unwrap_or(Path::new(""));
+    let mut seen = HashSet::new();
+    let mut sources = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let path = dir.join(line);
+        if !path.is_file() {
+            return Err(MergeError::ManifestFileNotFound(
+                path.display().to_string(),
+                manifest.display().to_string(),
+                index + 1,
+            )
+            .into());
+        }
+        if seen.insert(path.clone()) {
+            sources.push(EnvSource::File(path));
+        }
+    }
+    Ok(sources)
+}
+
+/// Whether `path` is ignored by the `.gitignore` files of its directory and the
+/// directories above it, up to the root of its git repository. The nearest rule wins.
+/// `gitignores` caches the parsed files by directory
+fn is_gitignored(path: &Path, gitignores: &mut HashMap<PathBuf, Option<Gitignore>>) -> bool {
+    let Ok(path) = std::fs::canonicalize(path) else {
+        return false;
+    };
+    for dir in path.ancestors().skip(1) {
+        let gitignore = gitignores.entry(dir.to_path_buf()).or_insert_with(|| {
+            let file = dir.join(".gitignore");
+            file.is_file().then(|| {
+                let (gitignore, error) = Gitignore::new(&file);
+                if let Some(e) = error {
+                    warn!("Failed to read some rules of {:?}: {}", file, e);
+                }
+                gitignore
+            })
+        });
+        if let Some(gitignore) = gitignore {
+            match gitignore.matched_path_or_any_parents(&path, false) {
+                ignore::Match::Ignore(_) => return true,
+                ignore::Match::Whitelist(_) => return false,
+                ignore::Match::None => {}
+            }
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    false
+}
+
+/// The profiles in the name of an env file, the parts between its first and last
+/// dots: `dev` for `app.dev.env`, none for `app.env` or `.env`
+fn file_profiles(path: &Path) -> Vec<&str> {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Vec::new();
+    };
+    let parts: Vec<&str> = name.trim_start_matches('.').split('.').collect();
+    match parts.len() {
+        0..=2 => Vec::new(),
+        len => parts[1..len - 1].to_vec(),
+    }
+}
+
+/// The order of env files by name: case-insensitive, then case-sensitive for paths
+/// that only differ by case. Components are joined with `/` so that the order is
+/// the same on every platform
+fn path_sort_key(path: &Path) -> (String, String) {
+    let normalized = path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    (normalized.to_lowercase(), normalized)
+}
+
+/// The leading components of a glob pattern that contain no wildcards
+pub fn pattern_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| {
+            !component
+                .as_os_str()
+                .to_string_lossy()
+                .contains(['*', '?', '['])
+        })
+        .collect()
+}
+
+/// The number of directories between `base` and the file at `path`
+fn depth_below(path: &Path, base: &Path) -> usize {
+    let relative = path.strip_prefix(base).unwrap_or(path);
+    relative.components().count().saturating_sub(1)
+}
+
+/// Sort the paths by modification time, the sort is stable so equal times keep name order.
+/// Keeps the name order if any time can't be read.
+fn sort_by_mtime(env_paths: &mut Vec<PathBuf>) {
+    let mtimes = env_paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()))
+        .collect::<Result<Vec<_>, _>>();
+    match mtimes {
+        Ok(mtimes) => {
+            let mut sorted: Vec<_> = mtimes.into_iter().zip(env_paths.drain(..)).collect();
+            sorted.sort_by_key(|(mtime, _)| *mtime);
+            env_paths.extend(sorted.into_iter().map(|(_, path)| path));
+        }
+        Err(e) => warn!(
+            "Failed to read modification times, sorting env files by name: {}",
+            e
+        ),
+    }
+}
+
+/// Whether `path` is a symlink or sits in a symlinked directory below `base`
+fn through_symlink(path: &Path, base: &Path) -> bool {
+    let mut current = base.to_path_buf();
+    path.strip_prefix(base)
+        .unwrap_or(path)
+        .components()
+        .any(|component| {
+            current.push(component);
+            current
+                .symlink_metadata()
+                .is_ok_and(|metadata| metadata.file_type().is_symlink())
+        })
+}
+
+fn get_env_vars(patterns: &[String], options: &MergeOptions) -> Result<Vec<EnvVar>> {
+    // Files are read in parallel, the results keep the order of the sources so that
+    // the first failing file is always the one reported
+    let mut sources = get_env_sources(patterns, options)?;
+    if let Some(path) = &options.defaults_file {
+        sources.retain(|source| !matches!(source, EnvSource::File(file) if file == path));
+        sources.insert(0, EnvSource::Defaults(path.clone()));
+    }
+    sources.extend(options.merge_toml.iter().cloned().map(EnvSource::Toml));
+    let contents = sources
+        .into_par_iter()
+        .map(|source| {
+            info!("Reading env file: {}", source);
+            let content = source.read(options.input_encoding)?;
+            Ok((source, content))
+        })
+        .collect::<Vec<Result<_>>>()
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    let mut env_vars = collect_env_vars(contents, options)?;
+    if let Some(path) = &options.allow_keys_from {
+        let source = EnvSource::File(path.clone());
+        let allowed: HashSet<String> = parse_env_vars(
+            &source.read(options.input_encoding)?,
+            &source.to_string(),
+            options,
+        )?
+        .into_iter()
+        .map(|var| var.key)
+        .collect();
+        env_vars.retain(|var| {
+            let allow = allowed.contains(&var.key);
+            if !allow {
+                debug!(
+                    "Dropping {} from {}, not in {}",
+                    var.key, var.source, source
+                );
+            }
+            allow
+        });
+    }
+    if options.validate_keys {
+        if let Some(var) = env_vars
+            .iter()
+            .find(|var| !options.key_pattern.is_match(&var.key))
+        {
+            return Err(MergeError::InvalidKey(var.key.clone(), var.source.clone()).into());
+        }
+    }
+    if options.detect_case_collisions {
+        check_case_collisions(&env_vars)?;
+    }
+    if options.expand {
+        expand::expand_env_vars(&mut env_vars, options)?;
+    }
+    Ok(env_vars)
+}
+
+/// One `  KEY in a, b` line per duplicate key
+fn format_duplicates(duplicates: &[(String, Vec<String>)]) -> String {
+    duplicates
+        .iter()
+        .map(|(key, sources)| format!("\n  {} in {}", key, sources.join(", ")))
+        .collect()
+}
+
+/// Parse the `(source, content)` pairs and accumulate their variables in order,
+/// applying the duplicate policy across sources, keys of the `--defaults-file` are
+/// overridden without it. Parsing runs in parallel, the
+/// accumulation and its errors only depend on the order of `contents`.
+fn collect_env_vars(
+    contents: Vec<(EnvSource, String)>,
+    options: &MergeOptions,
+) -> Result<Vec<EnvVar>> {
+    let parsed = contents
+        .par_iter()
+        .map(|(source, content)| match source {
+            EnvSource::Toml(_) => parse_toml_vars(content, &source.to_string(), options),
+            _ => parse_env_vars(content, &source.to_string(), options),
+        })
+        .collect::<Vec<_>>();
+    let mut env_vars: Vec<EnvVar> = Vec::new();
+    let mut index_by_key: HashMap<String, usize> = HashMap::new();
+    let mut counts = Vec::new();
+    let mut duplicates: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut default_keys: HashSet<String> = HashSet::new();
+    for ((source, _), vars) in contents.iter().zip(parsed) {
+        let vars = vars?;
+        counts.push((source.to_string(), vars.len()));
+        if vars.is_empty() {
+            if options.error_on_empty {
+                return Err(MergeError::EmptyFile(source.to_string()).into());
+            }
+            warn!("{} has no env vars", source);
+        }
+        let namespace = dir_namespace(source, options);
+        for mut var in vars {
+            if let Some(namespace) = &namespace {
+                var.key = format!("{}{}", namespace, var.key);
+            }
+            let Some(&index) = index_by_key.get(&var.key) else {
+                if matches!(source, EnvSource::Defaults(_)) {
+                    default_keys.insert(var.key.clone());
+                }
+                index_by_key.insert(var.key.clone(), env_vars.len());
+                env_vars.push(var);
+                continue;
+            };
+            let existing = &env_vars[index];
+            if default_keys.remove(&var.key) {
+                debug!(
+                    "Overriding default {}={} from {} with {} from {}",
+                    var.key,
+                    options.log_value(&var.key, &existing.value),
+                    existing.source,
+                    options.log_value(&var.key, &var.value),
+                    var.source
+                );
+                env_vars[index] = var;
+                continue;
+            }
+            if existing.raw_value == var.raw_value {
+                debug!(
+                    "Ignoring {} from {}, same value as in {}",
+                    var.key, var.source, existing.source
+                );
+                continue;
+            }
+            match options.on_duplicate {
+                DuplicatePolicy::Error if options.report_all_duplicates => {
+                    duplicates
+                        .entry(var.key)
+                        .or_insert_with(|| vec![existing.source.clone()])
+                        .push(var.source);
+                }
+                DuplicatePolicy::Error => {
+                    return Err(MergeError::DuplicateKey(
+                        var.key,
+                        var.source,
+                        existing.source.clone(),
+                    )
+                    .into());
+                }
+                DuplicatePolicy::FirstWins => {
+                    debug!(
+                        "Keeping {}={} from {}, ignoring {} from {}",
+                        var.key,
+                        options.log_value(&var.key, &existing.value),
+                        existing.source,
+                        options.log_value(&var.key, &var.value),
+                        var.source
+                    );
+                }
+                DuplicatePolicy::LastWins => {
+                    debug!(
+                        "Overriding {}={} from {} with {} from {}",
+                        var.key,
+                        options.log_value(&var.key, &existing.value),
+                        existing.source,
+                        options.log_value(&var.key, &var.value),
+                        var.source
+                    );
+                    env_vars[index] = var;
+                }
+            }
+        }
+    }
+    if !duplicates.is_empty() {
+        return Err(MergeError::DuplicateKeys(duplicates.into_iter().collect()).into());
+    }
+    if options.trace_sources {
+        trace_sources(&counts, &env_vars)
+            .iter()
+            .for_each(|line| info!("{}", line));
+    }
+    if options.key_order() == OutputSort::Alpha {
+        env_vars.sort_by(|a, b| compare_keys(&a.key, &b.key, options));
+    }
+    Ok(env_vars)
+}
+
+/// The lines logged by `--trace-sources`: the sources in merge order, with the number
+/// of keys read from each and how many of them are merged
+fn trace_sources(counts: &[(String, usize)], env_vars: &[EnvVar]) -> Vec<String> {
+    let mut lines = vec![format!("Merge order of {} env files:", counts.len())];
+    for (index, (source, count)) in counts.iter().enumerate() {
+        let kept = env_vars.iter().filter(|var| &var.source == source).count();
+        lines.push(format!(
+            "  {}. {}: {} keys, {} merged",
+            index + 1,
+            source,
+            count,
+            kept
+        ));
+    }
+    lines
+}
+
+/// The prefix of the keys read from `source` with `--namespace-by-dir`, for env
+/// files in a directory
+fn dir_namespace(source: &EnvSource, options: &MergeOptions) -> Option<String> {
+    let EnvSource::File(path) = source else {
+        return None;
+    };
+    if !options.namespace_by_dir {
+        return None;
+    }
+    let dir = path.parent()?.file_name()?.to_string_lossy();
+    Some(options.namespace_case.apply(&dir) + &options.namespace_separator)
+}
+
+/// Parse the env `content` read from `source` into variables, in file order
+fn parse_env_vars(content: &str, source: &str, options: &MergeOptions) -> Result<Vec<EnvVar>> {
+    let mut env_vars = Vec::new();
+    let parse_options = ParseOptions {
+        strict: options.strict,
+        separator: options.separator,
+        strip_inline_comments: options.strip_inline_comments,
+        json_values: options.parse_json,
+    };
+    let entries = parse_env_str_with(content, &parse_options)
+        .map_err(|e| MergeError::ParseFailed(source.to_owned(), e))?;
+    for entry in entries {
+        let lines = if options.no_trim {
+            entry.value.split('\n').collect::<Vec<_>>()
+        } else {
+            entry.value.trim().lines().collect()
+        };
+        let value = if options.multiline_strings {
+            lines.join("\n")
+        } else {
+            if lines
+                .iter()
+                .any(|line| line.contains(&options.multiline_delimiter))
+            {
+                return Err(MergeError::DelimiterInValue(
+                    entry.key,
+                    source.to_owned(),
+                    options.multiline_delimiter.clone(),
+                )
+                .into());
+            }
+            lines.join(&options.multiline_delimiter)
+        };
+        env_vars.push(EnvVar {
+            key: entry.key,
+            value,
+            raw_value: entry.value,
+            quoted: entry.quoted,
+            source: source.to_owned(),
+            typed_value: None,
+            comments: entry.comments,
+        });
+    }
+    Ok(env_vars)
+}
+
+/// The variables of the section of a TOML document, sub-tables give dotted keys.
+/// Strings are joined like env values, other values keep their TOML type.
+fn parse_toml_vars(content: &str, source: &str, options: &MergeOptions) -> Result<Vec<EnvVar>> {
+    let config: toml::value::Table =
+        toml::from_str(content).with_context(|| format!("Failed to parse {}", source))?;
+    let path = options.section_path();
+    let mut section = &config;
+    for (i, key) in path.iter().enumerate() {
+        let found = match section.get(*key) {
+            Some(Value::Table(table)) => Ok(table),
+            Some(_) => Err(MergeError::SectionTypeConflict(path[..=i].join("."))),
+            None => Err(MergeError::SectionNotFound(options.section.clone())),
+        };
+        section = found.with_context(|| format!("Failed to read {}", source))?;
+    }
+    let mut env_vars = Vec::new();
+    collect_toml_vars(section, "", source, options, &mut env_vars);
+    Ok(env_vars)
+}
+
+fn collect_toml_vars(
+    table: &toml::value::Table,
+    prefix: &str,
+    source: &str,
+    options: &MergeOptions,
+    env_vars: &mut Vec<EnvVar>,
+) {
+    for (key, value) in table {
+        let key = format!("{}{}", prefix, key);
+        let (value, typed_value) = match value {
+            Value::Table(table) => {
+                collect_toml_vars(table, &format!("{}.", key), source, options, env_vars);
+                continue;
+            }
+            Value::String(value) if options.multiline_strings => (value.clone(), None),
+            Value::String(value) => (value.replace('\n', &options.multiline_delimiter), None),
+            value => (value.to_string(), Some(value.clone())),
+        };
+        env_vars.push(EnvVar {
+            key,
+            raw_value: value.clone(),
+            value,
+            quoted: typed_value.is_none(),
+            source: source.to_owned(),
+            typed_value,
+            comments: Vec::new(),
+        });
+    }
+}
+
+/// Fail on the first two keys that only differ by case
+fn check_case_collisions(env_vars: &[EnvVar]) -> Result<(), MergeError> {
+    let mut by_lowercase: HashMap<String, &EnvVar> = HashMap::new();
+    for var in env_vars {
+        if let Some(other) = by_lowercase.insert(var.key.to_lowercase(), var) {
+            return Err(MergeError::CaseCollision(
+                other.key.clone(),
+                var.key.clone(),
+                other.source.clone(),
+                var.source.clone(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Add the `--default` values of the keys the env files don't define
+fn apply_defaults(env_vars: &mut Vec<EnvVar>, options: &MergeOptions) {
+    let mut keys: HashSet<String> = env_vars.iter().map(|var| var.key.clone()).collect();
+    let mut applied = false;
+    for (key, value) in &options.defaults {
+        if !keys.insert(key.clone()) {
+            continue;
+        }
+        debug!("Using the default value for {}", key);
+        env_vars.push(EnvVar {
+            key: key.clone(),
+            value: value.clone(),
+            raw_value: value.clone(),
+            quoted: false,
+            source: "<default>".to_owned(),
+            typed_value: None,
+            comments: Vec::new(),
+        });
+        applied = true;
+    }
+    if applied && options.key_order() == OutputSort::Alpha {
+        env_vars.sort_by(|a, b| compare_keys(&a.key, &b.key, options));
+    }
+}
+
+/// Fail with all the `--require` keys missing from the merged env vars
+fn check_required_keys(env_vars: &[EnvVar], options: &MergeOptions) -> Result<(), MergeError> {
+    let keys: HashSet<&str> = env_vars.iter().map(|var| var.key.as_str()).collect();
+    let missing: Vec<String> = options
+        .require
+        .iter()
+        .filter(|key| !keys.contains(key.as_str()))
+        .cloned()
+        .collect();
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(MergeError::MissingRequiredKeys(missing)),
+    }
+}
+
+/// Apply the key filter, prefix stripping, renames and key case of the options to the
+/// collected env vars
+fn select_env_vars(
+    mut env_vars: Vec<EnvVar>,
+    options: &MergeOptions,
+) -> Result<Vec<EnvVar>, MergeError> {
+    if let Some(prefix) = &options.prefix_filter {
+        let total = env_vars.len();
+        env_vars.retain(|var| var.key.starts_with(prefix.as_str()));
+        debug!(
+            "Kept {} of {} env vars starting with {}",
+            env_vars.len(),
+            total,
+            prefix
+        );
+    }
+    if let Some(prefix) = &options.strip_prefix {
+        let mut sources: HashMap<String, String> = HashMap::new();
+        for var in env_vars.iter_mut() {
+            match var.key.strip_prefix(prefix.as_str()) {
+                Some("") => warn!(
+                    "Not stripping {} from {}, the key would be empty",
+                    prefix, var.key
+                ),
+                Some(stripped) => var.key = stripped.to_owned(),
+                None => {}
+            }
+            if let Some(existing) = sources.insert(var.key.clone(), var.source.clone()) {
+                return Err(MergeError::DuplicateKey(
+                    var.key.clone(),
+                    var.source.clone(),
+                    existing,
+                ));
+            }
+        }
+    }
+    if !options.renames.is_empty() {
+        let renames: HashMap<&str, &str> = options
+            .renames
+            .iter()
+            .map(|(old, new)| (old.as_str(), new.as_str()))
+            .collect();
+        let mut sources: HashMap<String, String> = HashMap::new();
+        for var in env_vars.iter_mut() {
+            if let Some(&new) = renames.get(var.key.as_str()) {
+                debug!("Renaming {} from {} to {}", var.key, var.source, new);
+                var.key = new.to_owned();
+            }
+            if let Some(existing) = sources.insert(var.key.clone(), var.source.clone()) {
+                return Err(MergeError::DuplicateKey(
+                    var.key.clone(),
+                    var.source.clone(),
+                    existing,
+                ));
+            }
+        }
+    }
+    if options.key_case != KeyCase::Keep {
+        let mut originals: HashMap<String, String> = HashMap::new();
+        for var in env_vars.iter_mut() {
+            let key = options.key_case.apply(&var.key);
+            let original = std::mem::replace(&mut var.key, key);
+            if let Some(other) = originals.insert(var.key.clone(), original.clone()) {
+                return Err(MergeError::KeyCaseCollision(
+                    other,
+                    original,
+                    var.key.clone(),
+                ));
+            }
+        }
+    }
+    if (options.strip_prefix.is_some()
+        || !options.renames.is_empty()
+        || options.key_case != KeyCase::Keep)
+        && options.key_order() == OutputSort::Alpha
+    {
+        env_vars.sort_by(|a, b| compare_keys(&a.key, &b.key, options));
+    }
+    Ok(env_vars)
+}
+
+fn merge_existing(
+    env_vars: &[EnvVar],
+    file_content: &str,
+    options: &MergeOptions,
+) -> Result<(String, Value, MergeReport)> {
+    match options.format {
+        OutputFormat::Toml => toml_file::merge_existing_toml(env_vars, file_content, options),
+        OutputFormat::Json => json::merge_existing_json(env_vars, file_content, options),
+        OutputFormat::Yaml => yaml::merge_existing_yaml(env_vars, file_content, options),
+        OutputFormat::Dotenv => dotenv::merge_existing_dotenv(env_vars, file_content, options),
+    }
+}
+
+/// Insert the env vars into the section table, reporting how they changed it
+fn merge_env_table(
+    env_table: &mut toml::value::Table,
+    env_vars: &[EnvVar],
+    options: &MergeOptions,
+) -> Result<MergeReport> {
+    if options.no_shrink {
+        check_no_shrink(env_table, env_vars, options)?;
+    }
+    let mut report = MergeReport::default();
+    let mut keys_by_path: HashMap<Vec<&str>, &str> = HashMap::new();
+    for var in env_vars {
+        let (key, value) = (&var.key, options.log_value(&var.key, &var.value));
+        let mut value_toml = to_toml_value(var, options)?;
+        let path = key_path(key, options);
+        if let Some(other) = keys_by_path.insert(path.clone(), key) {
+            return Err(MergeError::GroupCollision(
+                other.to_owned(),
+                key.to_owned(),
+                path.join("."),
+            )
+            .into());
+        }
+        let existing = match options.merge_strategy {
+            MergeStrategy::Deep => value_at_mut(env_table, &path),
+            MergeStrategy::ReplaceSection => None,
+        };
+        let old = match existing {
+            Some(existing) => {
+                value_toml = deep_merge(existing.clone(), value_toml, key, path.join("."))?;
+                Some(std::mem::replace(existing, value_toml.clone()))
+            }
+            None if options.nested || options.group_by.is_some() => {
+                insert_nested(env_table, key, &path, value_toml.clone())?
+            }
+            None => env_table.insert(key.to_owned(), value_toml.clone()),
+        };
+        match old {
+            Some(old) if old == value_toml => {
+                trace!("Unchanged env var: {}={}", key, value);
+                report.unchanged.push(key.to_owned());
+            }
+            Some(old) => {
+                debug!("Updating env var: {}={}", key, value);
+                trace!("Old value: {}", options.log_value(key, &old.to_string()));
+                report.updated.push(UpdatedKey {
+                    key: key.to_owned(),
+                    old,
+                    new: value_toml,
+                });
+            }
+            None => {
+                debug!("Adding env var: {}={}", key, value);
+                report.added.push(AddedKey {
+                    key: key.to_owned(),
+                    value: value_toml,
+                });
+            }
+        }
+    }
+    if options.replace_env {
+        let merged = merged_paths(env_vars, options);
+        prune_section(env_table, "", &merged, options, &mut report.removed);
+    }
+    Ok(report)
+}
+
+/// Fail on the keys of the section that none of the env vars sets, by their dotted
+/// path. Tables are only looked into when keys are nested or grouped
+fn check_no_shrink(
+    env_table: &toml::value::Table,
+    env_vars: &[EnvVar],
+    options: &MergeOptions,
+) -> Result<(), MergeError> {
+    fn leaf_paths(table: &toml::value::Table, prefix: &str, nested: bool, paths: &mut Vec<String>) {
+        for (key, value) in table {
+            let path = format!("{}{}", prefix, key);
+            match value {
+                Value::Table(table) if nested => {
+                    leaf_paths(table, &format!("{}.", path), nested, paths)
+                }
+                _ => paths.push(path),
+            }
+        }
+    }
+    let merged = merged_paths(env_vars, options);
+    let mut paths = Vec::new();
+    let nested = options.nested || options.group_by.is_some();
+    leaf_paths(env_table, "", nested, &mut paths);
+    let removed: Vec<String> = paths
+        .into_iter()
+        .filter(|path| !merged.contains(path))
+        .collect();
+    match removed.is_empty() {
+        true => Ok(()),
+        false => Err(MergeError::SectionShrinks(removed)),
+    }
+}
+
+/// The dotted paths in the section of the env vars
+fn merged_paths(env_vars: &[EnvVar], options: &MergeOptions) -> HashSet<String> {
+    env_vars
+        .iter()
+        .map(|var| key_path(&var.key, options).join("."))
+        .collect()
+}
+
+/// Remove the keys of the section that none of the env vars sets for `--replace-env`,
+/// and the tables left empty. The remaining keys keep their order
+fn prune_section(
+    table: &mut toml::value::Table,
+    prefix: &str,
+    merged: &HashSet<String>,
+    options: &MergeOptions,
+    removed: &mut Vec<RemovedKey>,
+) {
+    let nested = options.nested || options.group_by.is_some();
+    *table = std::mem::take(table)
+        .into_iter()
+        .filter_map(|(key, mut value)| {
+            let path = format!("{}{}", prefix, key);
+            if merged.contains(&path) {
+                return Some((key, value));
+            }
+            match &mut value {
+                Value::Table(table) if nested => {
+                    prune_section(table, &format!("{}.", path), merged, options, removed);
+                    (!table.is_empty()).then_some((key, value))
+                }
+                _ => {
+                    debug!("Removing env var no longer in the env files: {}", path);
+                    removed.push(RemovedKey { key: path, value });
+                    None
+                }
+            }
+        })
+        .collect();
+}
+
+/// The order of env keys, case-insensitive unless `--case-sensitive-sort` is set
+fn compare_keys(a: &str, b: &str, options: &MergeOptions) -> Ordering {
+    match options.case_sensitive_sort {
+        true => a.cmp(b),
+        false => a
+            .to_lowercase()
+            .cmp(&b.to_lowercase())
+            .then_with(|| a.cmp(b)),
+    }
+}
+
+/// Order the keys of the merged section for `--output-sort`, the env vars are in
+/// merge order unless sorted by name
+pub(crate) fn order_section(env_table: &mut Value, env_vars: &[EnvVar], options: &MergeOptions) {
+    match options.key_order() {
+        OutputSort::Alpha => sort_value(env_table, &|a, b| compare_keys(a, b, options)),
+        OutputSort::Insertion => {
+            let paths: Vec<Vec<&str>> = env_vars
+                .iter()
+                .map(|var| key_path(&var.key, options))
+                .collect();
+            if let Value::Table(table) = env_table {
+                order_by_paths(table, &paths);
+            }
+        }
+        OutputSort::Unsorted => {}
+    }
+}
+
+/// Order the keys of `table` by the first of `paths` they start, recursively.
+/// Keys not in `paths` go last and keep their order
+fn order_by_paths(table: &mut toml::value::Table, paths: &[Vec<&str>]) {
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for path in paths {
+        let next = positions.len();
+        positions.entry(path[0]).or_insert(next);
+    }
+    let mut entries: Vec<_> = std::mem::take(table).into_iter().collect();
+    entries.sort_by_key(|(key, _)| positions.get(key.as_str()).copied().unwrap_or(usize::MAX));
+    for (key, value) in entries.iter_mut() {
+        if let Value::Table(table) = value {
+            let children: Vec<Vec<&str>> = paths
+                .iter()
+                .filter(|path| path.len() > 1 && path[0] == key)
+                .map(|path| path[1..].to_vec())
+                .collect();
+            order_by_paths(table, &children);
+        }
+    }
+    *table = entries.into_iter().collect();
+}
+
+/// Sort the keys of all tables below `value` with `compare`
+fn sort_value(value: &mut Value, compare: &dyn Fn(&str, &str) -> Ordering) {
+    match value {
+        Value::Table(table) => {
+            let mut entries: Vec<_> = std::mem::take(table).into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| compare(a, b));
+            for (_, value) in entries.iter_mut() {
+                sort_value(value, compare);
+            }
+            *table = entries.into_iter().collect();
+        }
+        Value::Array(array) => array
+            .iter_mut()
+            .for_each(|value| sort_value(value, compare)),
+        _ => {}
+    }
+}
+
+/// The path of the key in the section, split on `.` with `--nested` and on the
+/// `--group-by` separator
+pub(crate) fn key_path<'a>(key: &'a str, options: &MergeOptions) -> Vec<&'a str> {
+    let grouped = options
+        .group_by
+        .as_deref()
+        .and_then(|separator| key.split_once(separator))
+        .filter(|(group, leaf)| !group.is_empty() && !leaf.is_empty());
+    let (mut path, leaf) = match grouped {
+        Some((group, leaf)) => (vec![group], leaf),
+        None => (Vec::new(), key),
+    };
+    match options.nested {
+        true => path.extend(leaf.split('.')),
+        false => path.push(leaf),
+    }
+    path
+}
+
+/// Insert `value` at the dotted `key` below `table`, creating intermediate tables.
+/// Returns the replaced value, if any.
+fn insert_nested(
+    table: &mut toml::value::Table,
+    key: &str,
+    path: &[&str],
+    value: Value,
+) -> Result<Option<Value>, MergeError> {
+    let (leaf, parents) = path.split_last().unwrap();
+    let mut table = table;
+    for (index, part) in parents.iter().enumerate() {
+        table = match table
+            .entry(part.to_string())
+            .or_insert_with(|| Value::Table(toml::value::Table::new()))
+        {
+            Value::Table(table) => table,
+            _ => {
+                return Err(MergeError::KeyConflict(
+                    key.to_owned(),
+                    path[..=index].join("."),
+                ))
+            }
+        };
+    }
+    if let Some(Value::Table(existing)) = table.get(*leaf) {
+        if let Some(child) = existing.keys().next() {
+            return Err(MergeError::KeyConflict(
+                key.to_owned(),
+                format!("{}.{}", key, child),
+            ));
+        }
+    }
+    Ok(table.insert(leaf.to_string(), value))
+}
+
+/// The value at `path` below `table`, if every parent is a table
+fn value_at_mut<'a>(table: &'a mut toml::value::Table, path: &[&str]) -> Option<&'a mut Value> {
+    let (leaf, parents) = path.split_last()?;
+    let mut table = table;
+    for part in parents {
+        table = table.get_mut(*part)?.as_table_mut()?;
+    }
+    table.get_mut(*leaf)
+}
+
+/// Merge `new` into `old` for `--merge-strategy deep`, `path` is where they are
+/// found in the section
+fn deep_merge(old: Value, new: Value, key: &str, path: String) -> Result<Value, MergeError> {
+    match (old, new) {
+        (Value::Table(mut old), Value::Table(new)) => {
+            for (child, value) in new {
+                let child_path = format!("{}.{}", path, child);
+                match old.get_mut(&child) {
+                    Some(existing) => {
+                        let existing_value = std::mem::replace(existing, Value::Boolean(false));
+                        *existing = deep_merge(existing_value, value, key, child_path)?;
+                    }
+                    None => {
+                        old.insert(child, value);
+                    }
+                }
+            }
+            Ok(Value::Table(old))
+        }
+        (Value::Table(_), _) | (_, Value::Table(_)) => {
+            Err(MergeError::KeyConflict(key.to_owned(), path))
+        }
+        (_, new) => Ok(new),
+    }
+}
+
+/// Convert an env value to TOML, with the type of the `--schema` if any, inferring
+/// its type under `--typed` otherwise. Quoted values without a schema type stay strings.
+fn to_toml_value(var: &EnvVar, options: &MergeOptions) -> Result<Value, MergeError> {
+    if let Some(value) = &var.typed_value {
+        return Ok(value.clone());
+    }
+    let value = cased_value(var, options);
+    check_control_chars(&value, var, options)?;
+    if let Some(schema) = &options.schema {
+        let Some(schema_type) = schema.get(&var.key) else {
+            return Ok(Value::String(value));
+        };
+        let delimiter = options.array_delimiter.as_deref().unwrap_or(",");
+        return schema_type.convert(&value, delimiter).ok_or_else(|| {
+            MergeError::TypeMismatch(
+                var.key.clone(),
+                schema_type.name().to_owned(),
+                options.log_value(&var.key, &value).to_owned(),
+            )
+        });
+    }
+    if options.parse_json {
+        let json = match options.multiline_strings {
+            true => serde_json::from_str(&value),
+            false => serde_json::from_str(&value.replace(&options.multiline_delimiter, "\n")),
+        };
+        let json = json
+            .ok()
+            .filter(|json: &serde_json::Value| json.is_object() || json.is_array());
+        if let Some(value) = json.and_then(json_to_toml) {
+            return Ok(value);
+        }
+    }
+    if var.quoted {
+        return Ok(Value::String(value));
+    }
+    Ok(match options.array_delimiter.as_deref() {
+        Some(delimiter) if value.contains(delimiter) => Value::Array(
+            value
+                .split(delimiter)
+                .filter(|element| !element.is_empty())
+                .map(|element| to_scalar_value(element, options))
+                .collect(),
+        ),
+        _ => to_scalar_value(&value, options),
+    })
+}
+
+/// Warn about or fail on the first control character of the value for `--control-chars`
+fn check_control_chars(
+    value: &str,
+    var: &EnvVar,
+    options: &MergeOptions,
+) -> Result<(), MergeError> {
+    let Some(c) = value.chars().find(|&c| c.is_control() && c != '\n') else {
+        return Ok(());
+    };
+    match options.control_chars {
+        ControlChars::Keep => Ok(()),
+        ControlChars::Warn => {
+            warn!(
+                "Value of {} in {} contains the control character {:?}",
+                var.key, var.source, c
+            );
+            Ok(())
+        }
+        ControlChars::Error => Err(MergeError::ControlCharacter(
+            var.key.clone(),
+            var.source.clone(),
+            c,
+        )),
+    }
+}
+
+/// The value with the `--value-case` applied. With `--no-trim`, quoted and multi-line
+/// values are kept as written
+fn cased_value(var: &EnvVar, options: &MergeOptions) -> String {
+    let preserved = options.no_trim && (var.quoted || var.raw_value.contains('\n'));
+    match preserved {
+        true => var.value.clone(),
+        false => options.value_case.apply(&var.value),
+    }
+}
+
+/// The TOML value of a JSON value, `None` for null
+fn json_to_toml(json: serde_json::Value) -> Option<Value> {
+    Some(match json {
+        serde_json::Value::Null => return None,
+        serde_json::Value::Bool(boolean) => Value::Boolean(boolean),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(integer) => Value::Integer(integer),
+            None => Value::Float(number.as_f64()?),
+        },
+        serde_json::Value::String(string) => Value::String(string),
+        serde_json::Value::Array(array) => {
+            Value::Array(array.into_iter().filter_map(json_to_toml).collect())
+        }
+        serde_json::Value::Object(object) => Value::Table(
+            object
+                .into_iter()
+                .filter_map(|(key, value)| Some((key, json_to_toml(value)?)))
+                .collect(),
+        ),
+    })
+}
+
+/// The value with its datetimes as strings, for formats without a datetime type.
+/// Serializing them as is gives a private wrapper object
+pub(crate) fn datetimes_as_strings(value: &Value) -> Value {
+    match value {
+        Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        Value::Array(array) => Value::Array(array.iter().map(datetimes_as_strings).collect()),
+        Value::Table(table) => Value::Table(
+            table
+                .iter()
+                .map(|(key, value)| (key.clone(), datetimes_as_strings(value)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+/// An unquoted value, as a number, boolean or datetime with `--typed` when it parses as one
+fn to_scalar_value(value: &str, options: &MergeOptions) -> Value {
+    if options.typed {
+        if let Some(number) = to_number(value) {
+            return number;
+        }
+        if let Ok(boolean) = value.parse::<bool>() {
+            return Value::Boolean(boolean);
+        }
+        if let Ok(datetime) = value.parse::<toml::value::Datetime>() {
+            return Value::Datetime(datetime);
+        }
+    }
+    Value::String(value.to_owned())
+}
+
+/// The integer or finite float of a value without a `+` sign or leading zeros, which
+/// parsing would drop from values like zip codes or file modes
+fn to_number(value: &str) -> Option<Value> {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    let mut chars = digits.chars();
+    match (chars.next(), chars.next()) {
+        (Some('+'), _) => return None,
+        (Some('0'), Some(c)) if c.is_ascii_digit() => return None,
+        _ => {}
+    }
+    if let Ok(integer) = value.parse::<i64>() {
+        return Some(Value::Integer(integer));
+    }
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|float| float.is_finite())
+        .map(Value::Float)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn test_merge_env_into_toml_new() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let config_content = merge_env_into_toml(&patterns, "").unwrap();
+        let verify_content = std::fs::read_to_string("src/test_data/new_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_existing() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let existing = std::fs::read_to_string("src/test_data/old.toml").unwrap();
+        let config_content = merge_env_into_toml(&patterns, &existing).unwrap();
+        let verify_content = std::fs::read_to_string("src/test_data/old_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_keeps_comments() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let existing = std::fs::read_to_string("src/test_data/commented/config.toml").unwrap();
+        let config_content = merge_env_into_toml(&patterns, &existing).unwrap();
+        let verify_content =
+            std::fs::read_to_string("src/test_data/commented/config_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_marker_placement() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let existing = std::fs::read_to_string("src/test_data/placement/config.toml").unwrap();
+        let config_content = merge_env_into_toml(&patterns, &existing).unwrap();
+        let verify_content =
+            std::fs::read_to_string("src/test_data/placement/config_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+
+        // Sub-tables of the section defined further down end up inside the markers
+        let existing = "[env]\nE = \"1\"\n\n[other]\nx = 1\n\n[env.sub]\ny = \"2\"\n";
+        let config_content = merge_env_into_toml(&patterns, existing).unwrap();
+        let lines: Vec<&str> = config_content.lines().collect();
+        let position = |line: &str| lines.iter().position(|l| *l == line).unwrap();
+        let end = position(DEFAULT_MARKER_END);
+        assert!(position("[env]") < end);
+        assert!(position("[env.sub]") < end);
+        assert!(end < position("[other]"));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_idempotent() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let count =
+            |content: &str, marker: &str| content.lines().filter(|line| *line == marker).count();
+        for existing in [
+            String::new(),
+            std::fs::read_to_string("src/test_data/placement/config.toml").unwrap(),
+            format!(
+                "{}\nname = \"app\"\n\n{}\n\n[test]\nX = 1\n",
+                DEFAULT_MARKER_START, DEFAULT_MARKER_END
+            ),
+        ] {
+            let once = merge_env_into_toml(&patterns, &existing).unwrap();
+            let twice = merge_env_into_toml(&patterns, &once).unwrap();
+            assert_eq!(once, twice);
+            assert_eq!(count(&twice, DEFAULT_MARKER_START), 1);
+            assert_eq!(count(&twice, DEFAULT_MARKER_END), 1);
+        }
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_section() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            section: "runtime_env".to_owned(),
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let verify_content = std::fs::read_to_string("src/test_data/section_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_nested_section() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            section: "app.runtime.env".to_owned(),
+            ..Default::default()
+        };
+        let existing = std::fs::read_to_string("src/test_data/nested_section/config.toml").unwrap();
+        let config_content = merge_env_into_toml_with(&patterns, &existing, &options).unwrap();
+        let verify_content =
+            std::fs::read_to_string("src/test_data/nested_section/config_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+        let again = merge_env_into_toml_with(&patterns, &config_content, &options).unwrap();
+        assert_eq!(again, config_content);
+
+        let json = MergeOptions {
+            format: OutputFormat::Json,
+            ..options.clone()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "{}", &json).unwrap();
+        let config: serde_json::Value = serde_json::from_str(&config_content).unwrap();
+        assert!(config["app"]["runtime"]["env"]["A"].is_string());
+
+        for format in [OutputFormat::Toml, OutputFormat::Json, OutputFormat::Yaml] {
+            let existing = match format {
+                OutputFormat::Json => "{\"app\": {\"runtime\": 3}}",
+                OutputFormat::Yaml => "app:\n  runtime: 3\n",
+                _ => "app = { runtime = 3 }",
+            };
+            let options = MergeOptions {
+                format,
+                force_section: true,
+                ..options.clone()
+            };
+            let result = merge_env_into_toml_with(&patterns, existing, &options)
+                .err()
+                .unwrap();
+            assert_eq!(
+                result.to_string(),
+                MergeError::SectionTypeConflict("app.runtime".to_owned()).to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_invalid_section() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        for section in ["", "runtime env", "a..b", ".a", "a."] {
+            let options = MergeOptions {
+                section: section.to_owned(),
+                ..Default::default()
+            };
+            let result = merge_env_into_toml_with(&patterns, "", &options)
+                .err()
+                .unwrap();
+            assert_eq!(
+                result.to_string(),
+                MergeError::InvalidSection(section.to_owned()).to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_multiple_patterns() {
+        let patterns = vec![
+            "src/test_data/1.env".to_owned(),
+            "src/test_data/2.env".to_owned(),
+            "src/test_data/[0-9].env".to_owned(),
+        ];
+        let config_content = merge_env_into_toml(&patterns, "").unwrap();
+        let verify_content = std::fs::read_to_string("src/test_data/new_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_duplicate_across_patterns() {
+        let patterns = vec![
+            "src/test_data/duplicate.env".to_owned(),
+            "src/test_data/1.env".to_owned(),
+        ];
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::DuplicateKey(
+                "A".to_owned(),
+                Path::new("src/test_data/duplicate.env")
+                    .display()
+                    .to_string(),
+                Path::new("src/test_data/1.env").display().to_string(),
+            )
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_no_file_found() {
+        let patterns = vec![
+            "src/test_data/*.missing".to_owned(),
+            "src/test_data/".to_owned(),
+        ];
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            "No file found for the pattern: src/test_data/*.missing, src/test_data/"
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_exclude() {
+        let patterns = vec!["src/test_data/*.env".to_owned()];
+        let options = MergeOptions {
+            exclude: vec!["src/test_data/duplicate.env".to_owned()],
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let verify_content = std::fs::read_to_string("src/test_data/new_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+
+        let options = MergeOptions {
+            exclude: vec!["src/test_data/*".to_owned()],
+            ..Default::default()
+        };
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::NoFileFound(patterns[0].clone()).to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_multiline_delimiter() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            multiline_delimiter: ";".to_owned(),
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let verify_content =
+            std::fs::read_to_string("src/test_data/delimiter_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_delimiter_in_value() {
+        let patterns = vec!["src/test_data/2.env".to_owned()];
+        let options = MergeOptions {
+            multiline_delimiter: "ddd".to_owned(),
+            ..Default::default()
+        };
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::DelimiterInValue(
+                "C".to_owned(),
+                Path::new("src/test_data/2.env").display().to_string(),
+                "ddd".to_owned()
+            )
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_multiline_strings() {
+        let patterns = vec!["src/test_data/multiline/pem.env".to_owned()];
+        let options = MergeOptions {
+            multiline_strings: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        assert!(config_content.starts_with(DEFAULT_MARKER_START));
+        assert!(config_content.ends_with(&format!("\n{}\n", DEFAULT_MARKER_END)));
+
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(
+            config["env"]["CERT"].as_str().unwrap(),
+            "-----BEGIN CERTIFICATE-----\n\
+             MIIBszCCAVmgAwIBAgIUEXAMPLE\n\
+             c2VsZi1zaWduZWQgY2VydA==\n\
+             -----END CERTIFICATE-----"
+        );
+        assert_eq!(config["env"]["NAME"].as_str().unwrap(), "server");
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_hash_lines_in_value() {
+        let patterns = vec!["src/test_data/multiline/script.env".to_owned()];
+        let options = MergeOptions {
+            multiline_strings: true,
+            strip_inline_comments: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        let env = config["env"].as_table().unwrap();
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["SCRIPT"].as_str(), Some("#!/bin/bash\necho hi"));
+        assert_eq!(env["NAME"].as_str(), Some("x"));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_parse_failed() {
+        let patterns = vec!["src/test_data/malformed/*.env".to_owned()];
+        let path = Path::new("src/test_data/malformed/broken.env")
+            .display()
+            .to_string();
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            format!(
+                "Failed to parse {}: Parse error at line 3: expected '=' after BROKEN",
+                path
+            )
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_detect_case_collisions() {
+        let patterns = vec!["src/test_data/case/*.env".to_owned()];
+        assert!(merge_env_into_toml(&patterns, "").is_ok());
+
+        let options = MergeOptions {
+            detect_case_collisions: true,
+            ..Default::default()
+        };
+        let path = Path::new("src/test_data/case/mixed.env")
+            .display()
+            .to_string();
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::CaseCollision("A".to_owned(), "a".to_owned(), path.clone(), path)
+                .to_string()
+        );
+
+        let patterns = vec!["src/test_data/case/inconsistent.env".to_owned()];
+        assert!(merge_env_into_toml_with(&patterns, "", &options).is_ok());
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_max_depth() {
+        let patterns = vec!["src/test_data/depth/**/*.env".to_owned()];
+        let keys = |max_depth: Option<usize>| {
+            let options = MergeOptions {
+                max_depth,
+                ..Default::default()
+            };
+            let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+            let config: Value = toml::from_str(&config_content).unwrap();
+            config["env"]
+                .as_table()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(None), vec!["A", "B", "C"]);
+        assert_eq!(keys(Some(1)), vec!["A", "B"]);
+        assert_eq!(keys(Some(0)), vec!["A"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_merge_env_into_toml_follow_symlinks() {
+        let folder = Path::new("src/test_data/symlinks");
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(folder.join("real")).unwrap();
+        std::fs::write(folder.join("real/a.env"), "A=1\n").unwrap();
+        std::fs::write(folder.join("b.env"), "B=2\n").unwrap();
+        let absolute = std::fs::canonicalize(folder).unwrap();
+        std::os::unix::fs::symlink(&absolute, folder.join("loop")).unwrap();
+
+        let patterns = vec!["src/test_data/symlinks/**/*.env".to_owned()];
+        let keys = |patterns: &[String], no_follow_symlinks: bool| {
+            let options = MergeOptions {
+                no_follow_symlinks,
+                ..Default::default()
+            };
+            let config_content = merge_env_into_toml_with(patterns, "", &options)?;
+            let config: Value = toml::from_str(&config_content).unwrap();
+            Ok::<_, anyhow::Error>(config["env"].as_table().unwrap().len())
+        };
+        // Following by default, the files reached through loop/ are only read once
+        assert_eq!(keys(&patterns, false).unwrap(), 2);
+        // Without following, they are skipped
+        assert_eq!(keys(&patterns, true).unwrap(), 2);
+
+        std::fs::remove_file(folder.join("loop")).unwrap();
+        std::os::unix::fs::symlink(absolute.join("real"), folder.join("linked")).unwrap();
+        let patterns = vec!["src/test_data/symlinks/l*/*.env".to_owned()];
+        assert_eq!(keys(&patterns, false).unwrap(), 1);
+        assert!(keys(&patterns, true).is_err());
+        let _ = std::fs::remove_dir_all(folder);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_sort_by_mtime() {
+        let folder = Path::new("src/test_data/mtime");
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(folder).unwrap();
+        let write = |name: &str, content: &str, age: u64| {
+            let path = folder.join(name);
+            std::fs::write(&path, content).unwrap();
+            let mtime = std::time::SystemTime::now() - std::time::Duration::from_secs(age);
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        };
+        write("a_new.env", "A=new\n", 10);
+        write("b_old.env", "A=old\n", 1000);
+
+        let patterns = vec!["src/test_data/mtime/*.env".to_owned()];
+        let value = |sort_by: SortBy| {
+            let options = MergeOptions {
+                sort_by,
+                on_duplicate: DuplicatePolicy::LastWins,
+                ..Default::default()
+            };
+            let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+            let config: Value = toml::from_str(&config_content).unwrap();
+            config["env"]["A"].as_str().unwrap().to_owned()
+        };
+        assert_eq!(value(SortBy::Name), "old");
+        assert_eq!(value(SortBy::Mtime), "new");
+        let _ = std::fs::remove_dir_all(folder);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_files() {
+        let files = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| Path::new("src/test_data/layered").join(name))
+                .collect::<Vec<_>>()
+        };
+        let options = MergeOptions {
+            files: files(&["override.env", "base.env"]),
+            on_duplicate: DuplicatePolicy::LastWins,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&[], "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["B"].as_str(), Some("2"));
+
+        let missing = files(&["missing.env"]);
+        let options = MergeOptions {
+            files: files(&["base.env", "missing.env"]),
+            ..options
+        };
+        let result = merge_env_into_toml_with(&[], "", &options).err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::FileNotFound(missing[0].display().to_string()).to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_many_files() {
+        let folder = Path::new("src/test_data/many");
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(folder).unwrap();
+        for index in 0..200 {
+            let content = format!("KEY_{:03}={}\nSHARED=same\n", index, index);
+            std::fs::write(folder.join(format!("{:03}.env", index)), content).unwrap();
+        }
+        let patterns = vec!["src/test_data/many/*.env".to_owned()];
+        let first = merge_env_into_toml(&patterns, "").unwrap();
+        for _ in 0..5 {
+            assert_eq!(merge_env_into_toml(&patterns, "").unwrap(), first);
+        }
+        let config: Value = toml::from_str(&first).unwrap();
+        assert_eq!(config["env"].as_table().unwrap().len(), 201);
+
+        // The first duplicate in file order is reported, whatever thread finishes first
+        for index in [150, 50] {
+            let path = folder.join(format!("{:03}.env", index));
+            std::fs::write(&path, "SHARED=other\n").unwrap();
+        }
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::DuplicateKey(
+                "SHARED".to_owned(),
+                folder.join("050.env").display().to_string(),
+                folder.join("000.env").display().to_string()
+            )
+            .to_string()
+        );
+        let _ = std::fs::remove_dir_all(folder);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_typed() {
+        let patterns = vec!["src/test_data/typed/typed.env".to_owned()];
+        let options = MergeOptions {
+            typed: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let verify_content =
+            std::fs::read_to_string("src/test_data/typed/typed_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_nested() {
+        let patterns = vec!["src/test_data/nested/nested.env".to_owned()];
+        let options = MergeOptions {
+            nested: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let verify_content =
+            std::fs::read_to_string("src/test_data/nested/nested_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+
+        let patterns = vec!["src/test_data/nested/conflict.env".to_owned()];
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::KeyConflict("A.B".to_owned(), "A".to_owned()).to_string()
+        );
+
+        let existing = "[env.A]\nB = \"2\"\n";
+        let patterns = vec!["src/test_data/1.env".to_owned()];
+        let result = merge_env_into_toml_with(&patterns, existing, &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::KeyConflict("A".to_owned(), "A.B".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_on_duplicate() {
+        let patterns = vec!["src/test_data/layered/*.env".to_owned()];
+        let options = MergeOptions {
+            on_duplicate: DuplicatePolicy::FirstWins,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["A"].as_str(), Some("1"));
+        assert_eq!(config["env"]["B"].as_str(), Some("2"));
+        assert_eq!(config["env"]["C"].as_str(), Some("4"));
+
+        let options = MergeOptions {
+            on_duplicate: DuplicatePolicy::LastWins,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["A"].as_str(), Some("1"));
+        assert_eq!(config["env"]["B"].as_str(), Some("3"));
+        assert_eq!(config["env"]["C"].as_str(), Some("4"));
+
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::DuplicateKey(
+                "B".to_owned(),
+                Path::new("src/test_data/layered/override.env")
+                    .display()
+                    .to_string(),
+                Path::new("src/test_data/layered/base.env")
+                    .display()
+                    .to_string(),
+            )
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_identical_duplicate() {
+        let patterns = vec!["src/test_data/identical/*.env".to_owned()];
+        let config_content = merge_env_into_toml(&patterns, "").unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["A"].as_str(), Some("1"));
+        assert_eq!(config["env"]["B"].as_str(), Some("2"));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_invalid_existing() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let result = merge_env_into_toml(&patterns, "env = [1, 2]\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::SectionTypeConflict("env".to_owned()).to_string()
+        );
+
+        assert!(merge_env_into_toml(&patterns, "[1, 2]\n").is_err());
+        assert!(merge_env_into_toml(&patterns, "42\n").is_err());
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_scalar_section() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let existing = "env = \"production\"\n";
+        let result = merge_env_into_toml(&patterns, existing).err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::SectionTypeConflict("env".to_owned()).to_string()
+        );
+
+        let options = MergeOptions {
+            force_section: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, existing, &options).unwrap();
+        let verify_content = std::fs::read_to_string("src/test_data/new_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_summary() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions::default();
+        let summary = |existing: &str| {
+            let existing = std::fs::read_to_string(existing).unwrap();
+            merge_env_into_toml_with_summary(&patterns, &existing, &options)
+                .unwrap()
+                .1
+        };
+        let added = summary("src/test_data/old.toml");
+        assert_eq!(
+            added.to_string(),
+            "Merged 3 env vars: 3 added, 0 updated, 0 unchanged"
+        );
+        let updated = summary("src/test_data/overwrite.toml");
+        assert_eq!(
+            updated,
+            MergeSummary {
+                added: 0,
+                updated: 3,
+                unchanged: 0
+            }
+        );
+        let unchanged = summary("src/test_data/old_verify.toml");
+        assert_eq!(unchanged.unchanged, 3);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_preserve_order() {
+        let patterns = vec![
+            "src/test_data/order/second.env".to_owned(),
+            "src/test_data/order/first.env".to_owned(),
+        ];
+        let options = MergeOptions {
+            preserve_order: true,
+            ..Default::default()
+        };
+        let existing = "[env]\nEXISTING = \"1\"\n";
+        let config_content = merge_env_into_toml_with(&patterns, existing, &options).unwrap();
+        let verify_content =
+            std::fs::read_to_string("src/test_data/order/order_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+
+        let result =
+            merge_env_into_toml_with(&["src/test_data/layered/*.env".to_owned()], "", &options);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_case_sensitive_sort() {
+        let patterns = vec!["src/test_data/case/mixed.env".to_owned()];
+        let keys = |options: &MergeOptions| {
+            let config_content = merge_env_into_toml_with(&patterns, "", options).unwrap();
+            let config: Value = toml::from_str(&config_content).unwrap();
+            config["env"]
+                .as_table()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            keys(&MergeOptions::default()),
+            vec!["A", "a", "APP_KEY", "App_Key", "B", "b"]
+        );
+        let options = MergeOptions {
+            case_sensitive_sort: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            keys(&options),
+            vec!["A", "APP_KEY", "App_Key", "B", "a", "b"]
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_prefix_filter() {
+        let patterns = vec!["src/test_data/case/mixed.env".to_owned()];
+        let options = MergeOptions {
+            prefix_filter: Some("APP_".to_owned()),
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"].as_table().unwrap().len(), 1);
+        assert_eq!(config["env"]["APP_KEY"].as_str(), Some("6"));
+
+        let options = MergeOptions {
+            prefix_filter: Some("PAYMENTS_".to_owned()),
+            ..Default::default()
+        };
+        let (config_content, summary) =
+            merge_env_into_toml_with_summary(&patterns, "[env]\nX = \"1\"\n", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(summary.total(), 0);
+        assert_eq!(config["env"]["X"].as_str(), Some("1"));
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert!(config["env"].as_table().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_strip_prefix() {
+        let patterns = vec!["src/test_data/strip/payments.env".to_owned()];
+        let options = MergeOptions {
+            prefix_filter: Some("PAYMENTS_".to_owned()),
+            strip_prefix: Some("PAYMENTS_".to_owned()),
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        let env = config["env"].as_table().unwrap();
+        assert_eq!(env.keys().collect::<Vec<_>>(), vec!["API_KEY", "KEY"]);
+        assert_eq!(env["KEY"].as_str(), Some("b"));
+
+        let options = MergeOptions {
+            prefix_filter: None,
+            ..options
+        };
+        let path = Path::new("src/test_data/strip/payments.env")
+            .display()
+            .to_string();
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::DuplicateKey("KEY".to_owned(), path.clone(), path).to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_key_case() {
+        let patterns = vec!["src/test_data/case/mixed.env".to_owned()];
+        let options = MergeOptions {
+            key_case: KeyCase::Upper,
+            ..Default::default()
+        };
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::KeyCaseCollision("A".to_owned(), "a".to_owned(), "A".to_owned())
+                .to_string()
+        );
+
+        let patterns = vec!["src/test_data/case/inconsistent.env".to_owned()];
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(
+            config["env"].as_table().unwrap().keys().collect::<Vec<_>>(),
+            vec!["API_KEY", "DB_HOST", "PORT"]
+        );
+        let options = MergeOptions {
+            key_case: KeyCase::Lower,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["db_host"].as_str(), Some("2"));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_expand() {
+        let patterns = vec!["src/test_data/expand/urls.env".to_owned()];
+        let options = MergeOptions {
+            expand: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["FULL_URL"].as_str(), Some("localhost:8080"));
+        assert_eq!(config["env"]["API"].as_str(), Some("localhost:8080/api"));
+        assert_eq!(config["env"]["MISSING"].as_str(), Some("${NOPE}/x"));
+        assert_eq!(config["env"]["PRICE"].as_str(), Some("$5"));
+
+        let options = MergeOptions {
+            strict_expand: true,
+            ..options
+        };
+        let path = Path::new("src/test_data/expand/urls.env")
+            .display()
+            .to_string();
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::UnknownReference("NOPE".to_owned(), "MISSING".to_owned(), path).to_string()
+        );
+
+        let patterns = vec!["src/test_data/expand/cycle.env".to_owned()];
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::CyclicReference("A -> B -> A".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_use_process_env() {
+        let patterns = vec!["src/test_data/expand/process.env".to_owned()];
+        std::env::set_var("ENV_TO_CONFIG_TOML_TEST_HOST", "example.com");
+        let options = MergeOptions {
+            expand: true,
+            strict_expand: true,
+            ..Default::default()
+        };
+        assert!(merge_env_into_toml_with(&patterns, "", &options).is_err());
+
+        let options = MergeOptions {
+            use_process_env: true,
+            ..options
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(
+            config["env"]["URL"].as_str(),
+            Some("https://example.com/api")
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_annotate_source() {
+        let patterns = vec!["src/test_data/layered/*.env".to_owned()];
+        let options = MergeOptions {
+            annotate_source: true,
+            on_duplicate: DuplicatePolicy::LastWins,
+            ..Default::default()
+        };
+        let existing = "[env]\nOLD = \"1\"\n";
+        let config_content = merge_env_into_toml_with(&patterns, existing, &options).unwrap();
+        let source = |name: &str| {
+            Path::new("src/test_data/layered")
+                .join(name)
+                .display()
+                .to_string()
+        };
+        assert!(config_content.contains(&format!("A = \"1\"  # from {}\n", source("base.env"))));
+        assert!(config_content.contains(&format!("B = \"3\"  # from {}\n", source("override.env"))));
+        assert!(config_content.contains("OLD = \"1\"\n"));
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["C"].as_str(), Some("4"));
+
+        let patterns = vec!["src/test_data/multiline/pem.env".to_owned()];
+        let options = MergeOptions {
+            annotate_source: true,
+            multiline_strings: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        assert!(config_content.contains("\"\"\"  # from "));
+        toml::from_str::<Value>(&config_content).unwrap();
+    }
+
+    #[test]
+    fn test_collect_env_vars_stdin() {
+        let path = Path::new("src/test_data/1.env").display().to_string();
+        let contents = vec![
+            (
+                EnvSource::File(PathBuf::from(&path)),
+                std::fs::read_to_string(&path).unwrap(),
+            ),
+            (EnvSource::Stdin, "A=piped\nD=4\n".to_owned()),
+        ];
+        let result = collect_env_vars(contents.clone(), &MergeOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::DuplicateKey("A".to_owned(), "<stdin>".to_owned(), path).to_string()
+        );
+
+        let options = MergeOptions {
+            on_duplicate: DuplicatePolicy::LastWins,
+            ..Default::default()
+        };
+        let env_vars = collect_env_vars(contents, &options).unwrap();
+        let a = env_vars.iter().find(|var| var.key == "A").unwrap();
+        assert_eq!((a.value.as_str(), a.source.as_str()), ("piped", "<stdin>"));
+        assert_eq!(env_vars.len(), 3);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_require() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            require: vec![
+                "A".to_owned(),
+                "DATABASE_URL".to_owned(),
+                "SECRET".to_owned(),
+            ],
+            ..Default::default()
+        };
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::MissingRequiredKeys(vec!["DATABASE_URL".to_owned(), "SECRET".to_owned()])
+                .to_string()
+        );
+        assert_eq!(
+            result.to_string(),
+            "Missing required keys: DATABASE_URL, SECRET"
+        );
+
+        let options = MergeOptions {
+            require: vec!["A".to_owned(), "B".to_owned()],
+            ..Default::default()
+        };
+        assert!(merge_env_into_toml_with(&patterns, "", &options).is_ok());
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_defaults() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            defaults: vec![
+                ("A".to_owned(), "fallback".to_owned()),
+                ("LOG_LEVEL".to_owned(), "info".to_owned()),
+            ],
+            require: vec!["LOG_LEVEL".to_owned()],
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: toml::value::Table = toml::from_str(&config_content).unwrap();
+        let env = config["env"].as_table().unwrap();
+        assert_eq!(env["A"].as_str(), Some("# nice||||asd||||qwe"));
+        assert_eq!(env["LOG_LEVEL"].as_str(), Some("info"));
+        let keys: Vec<&String> = env.keys().collect();
+        let mut sorted = keys.clone();
+        sorted.sort_by_key(|key| key.to_lowercase());
+        assert_eq!(keys, sorted);
+
+        assert_eq!(
+            parse_default("URL=http://host/?a=1"),
+            Ok(("URL".to_owned(), "http://host/?a=1".to_owned()))
+        );
+        assert!(parse_default("=value").is_err());
+        assert!(parse_default("KEY").is_err());
+    }
+
+    #[test]
+    fn test_mask() {
+        let options = MergeOptions {
+            mask: true,
+            ..Default::default()
+        };
+        assert_eq!(options.log_value("DB_PASSWORD", "hunter2"), "***");
+        assert_eq!(options.log_value("API_KEY", "abc"), "***");
+        assert_eq!(options.log_value("HOST", "localhost"), "localhost");
+        assert_eq!(
+            MergeOptions::default().log_value("DB_PASSWORD", "hunter2"),
+            "hunter2"
+        );
+        let custom = MergeOptions {
+            mask_pattern: Regex::new("^PRIVATE_").unwrap(),
+            ..options.clone()
+        };
+        assert_eq!(custom.log_value("PRIVATE_CERT", "pem"), "***");
+        assert_eq!(custom.log_value("DB_PASSWORD", "hunter2"), "hunter2");
+
+        // Only the logs are masked
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            mask_pattern: Regex::new(".*").unwrap(),
+            ..options
+        };
+        assert_eq!(
+            merge_env_into_toml_with(&patterns, "", &options).unwrap(),
+            merge_env_into_toml(&patterns, "").unwrap()
+        );
+    }
+
+    /// Serve `body` to a single request on a local port, returning the URL
+    #[cfg(feature = "http")]
+    fn serve_once(body: &'static str) -> String {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/base.env", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+        });
+        url
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_merge_env_into_toml_url() {
+        let url = serve_once("REMOTE=1\nSHARED=\"from url\"\n");
+        let options = MergeOptions {
+            files: vec![PathBuf::from(&url)],
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&[], "", &options).unwrap();
+        assert!(config_content.contains("REMOTE = \"1\""));
+        assert!(config_content.contains("SHARED = \"from url\""));
+
+        let url = serve_once("A=remote\n");
+        let patterns = vec!["src/test_data/[0-9].env".to_owned(), url.clone()];
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        let path = Path::new("src/test_data/1.env").display().to_string();
+        assert_eq!(
+            result.to_string(),
+            MergeError::DuplicateKey("A".to_owned(), url, path).to_string()
+        );
+
+        // Nothing listens on the port of a dropped listener
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/base.env", listener.local_addr().unwrap());
+        drop(listener);
+        let patterns = vec![url.clone()];
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        assert!(matches!(
+            result.downcast_ref::<MergeError>(),
+            Some(MergeError::FetchFailed(failed, _)) if *failed == url
+        ));
+    }
+
+    #[cfg(not(feature = "http"))]
+    #[test]
+    fn test_merge_env_into_toml_url_without_http() {
+        let url = "https://config.example.com/base.env".to_owned();
+        let patterns = vec![url.clone()];
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::FetchFailed(url, "built without the http feature".to_owned()).to_string()
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_merge_env_into_toml_gzip() {
+        let patterns = vec!["src/test_data/gzip/bundle.env.gz".to_owned()];
+        let options = MergeOptions {
+            annotate_source: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let source = Path::new("src/test_data/gzip/bundle.env.gz").display();
+        assert!(config_content.contains(&format!("BUNDLE_NAME = \"artifacts\"  # from {}", source)));
+        assert!(config_content.contains("CERT = \"-----BEGIN-----||||abc||||-----END-----\""));
+
+        let patterns = vec!["src/test_data/gzip/broken.env.gz".to_owned()];
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        assert!(result
+            .to_string()
+            .starts_with("Failed to decompress src/test_data/gzip/broken.env.gz: "));
+    }
+
+    #[cfg(not(feature = "gzip"))]
+    #[test]
+    fn test_merge_env_into_toml_gzip_without_feature() {
+        let patterns = vec!["src/test_data/gzip/bundle.env.gz".to_owned()];
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::DecompressFailed(
+                "src/test_data/gzip/bundle.env.gz".to_owned(),
+                "built without the gzip feature".to_owned()
+            )
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_merge_toml() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            merge_toml: vec![PathBuf::from("src/test_data/merge_toml/layer.toml")],
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: toml::value::Table = toml::from_str(&config_content).unwrap();
+        let env = config["env"].as_table().unwrap();
+        assert_eq!(env["PORT"], Value::Integer(8080));
+        assert_eq!(env["DEBUG"], Value::Boolean(true));
+        assert_eq!(env["NAME"].as_str(), Some("layer"));
+        assert_eq!(env["NOTES"].as_str(), Some("first||||second"));
+        assert_eq!(env["DATABASE.HOST"].as_str(), Some("db"));
+        assert!(env.contains_key("A"));
+        assert!(!env.contains_key("IGNORED"));
+
+        let options = MergeOptions {
+            merge_toml: vec![PathBuf::from("src/test_data/merge_toml/duplicate.toml")],
+            ..Default::default()
+        };
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::DuplicateKey(
+                "A".to_owned(),
+                Path::new("src/test_data/merge_toml/duplicate.toml")
+                    .display()
+                    .to_string(),
+                Path::new("src/test_data/1.env").display().to_string()
+            )
+            .to_string()
+        );
+
+        let options = MergeOptions {
+            section: "missing".to_owned(),
+            merge_toml: vec![PathBuf::from("src/test_data/merge_toml/layer.toml")],
+            ..Default::default()
+        };
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            format!("{:#}", result),
+            "Failed to read src/test_data/merge_toml/layer.toml: Section missing not found"
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_group_by() {
+        let patterns = vec!["src/test_data/group/services.env".to_owned()];
+        let options = MergeOptions {
+            group_by: Some("_".to_owned()),
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let verify_content =
+            std::fs::read_to_string("src/test_data/group/services_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+
+        let env_vars = |keys: &[&str]| -> Vec<EnvVar> {
+            keys.iter()
+                .map(|key| EnvVar {
+                    key: key.to_string(),
+                    value: "1".to_owned(),
+                    raw_value: "1".to_owned(),
+                    quoted: false,
+                    source: "test.env".to_owned(),
+                    typed_value: None,
+                    comments: Vec::new(),
+                })
+                .collect()
+        };
+        let result = merge_env_table(
+            &mut toml::value::Table::new(),
+            &env_vars(&["DB", "DB_HOST"]),
+            &options,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::KeyConflict("DB_HOST".to_owned(), "DB".to_owned()).to_string()
+        );
+
+        let options = MergeOptions {
+            nested: true,
+            ..options
+        };
+        let result = merge_env_table(
+            &mut toml::value::Table::new(),
+            &env_vars(&["DB.HOST", "DB_HOST"]),
+            &options,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::GroupCollision(
+                "DB.HOST".to_owned(),
+                "DB_HOST".to_owned(),
+                "DB.HOST".to_owned()
+            )
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_array_delimiter() {
+        let content = "PORTS=80,443,8080\nHOSTS=a.com,,b.com,\nQUOTED=\"a,b\"\nSINGLE=80\n";
+        let env_vars = parse_env_vars(content, "test.env", &MergeOptions::default()).unwrap();
+        let options = MergeOptions {
+            array_delimiter: Some(",".to_owned()),
+            ..Default::default()
+        };
+        let mut table = toml::value::Table::new();
+        merge_env_table(&mut table, &env_vars, &options).unwrap();
+        let strings = |values: &[&str]| {
+            Value::Array(
+                values
+                    .iter()
+                    .map(|v| Value::String(v.to_string()))
+                    .collect(),
+            )
+        };
+        assert_eq!(table["PORTS"], strings(&["80", "443", "8080"]));
+        assert_eq!(table["HOSTS"], strings(&["a.com", "b.com"]));
+        assert_eq!(table["QUOTED"], Value::String("a,b".to_owned()));
+        assert_eq!(table["SINGLE"], Value::String("80".to_owned()));
+
+        let options = MergeOptions {
+            typed: true,
+            ..options
+        };
+        let mut table = toml::value::Table::new();
+        merge_env_table(&mut table, &env_vars, &options).unwrap();
+        assert_eq!(
+            table["PORTS"],
+            Value::Array(vec![
+                Value::Integer(80),
+                Value::Integer(443),
+                Value::Integer(8080)
+            ])
+        );
+        assert_eq!(table["SINGLE"], Value::Integer(80));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_parse_json() {
+        let content = r#"FLAGS='{"a":true,"b":1,"c":null,"d":{"e":"f"}}'
+LIST='[1, "two", null, 3.5]'
+FEATURE_FLAGS={"a":true,"b":1}
+UNQUOTED_LIST=[1, 2] 
+PORT=8080
+QUOTED_PORT="8080"
+DEBUG=true
+NAME='"quoted"'
+NOTHING=null
+PLAIN=hello
+"#;
+        let options = MergeOptions {
+            parse_json: true,
+            ..Default::default()
+        };
+        let env_vars = parse_env_vars(content, "test.env", &options).unwrap();
+        let mut table = toml::value::Table::new();
+        merge_env_table(&mut table, &env_vars, &options).unwrap();
+        let expected: toml::value::Table = toml::from_str(
+            r#"
+            FLAGS = { a = true, b = 1, d = { e = "f" } }
+            LIST = [1, "two", 3.5]
+            FEATURE_FLAGS = { a = true, b = 1 }
+            UNQUOTED_LIST = [1, 2]
+            PORT = "8080"
+            QUOTED_PORT = "8080"
+            DEBUG = "true"
+            NAME = '"quoted"'
+            NOTHING = "null"
+            PLAIN = "hello"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(table, expected);
+
+        let mut table = toml::value::Table::new();
+        merge_env_table(&mut table, &env_vars, &MergeOptions::default()).unwrap();
+        assert_eq!(
+            table["FLAGS"].as_str(),
+            Some(r#"{"a":true,"b":1,"c":null,"d":{"e":"f"}}"#)
+        );
+
+        // References in the JSON are expanded before parsing
+        let content = "HOST=db.internal\nDB='{\"host\":\"${HOST}\",\"port\":5432}'\n";
+        let mut env_vars = parse_env_vars(content, "test.env", &MergeOptions::default()).unwrap();
+        let options = MergeOptions {
+            expand: true,
+            ..options
+        };
+        expand::expand_env_vars(&mut env_vars, &options).unwrap();
+        let mut table = toml::value::Table::new();
+        merge_env_table(&mut table, &env_vars, &options).unwrap();
+        let db = table["DB"].as_table().unwrap();
+        assert_eq!(db["host"].as_str(), Some("db.internal"));
+        assert_eq!(db["port"].as_integer(), Some(5432));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_empty_values() {
+        let content = "DEBUG=\nFEATURE_X\nNAME=app\n";
+        let env_vars = parse_env_vars(content, "test.env", &MergeOptions::default()).unwrap();
+        let mut table = toml::value::Table::new();
+        merge_env_table(&mut table, &env_vars, &MergeOptions::default()).unwrap();
+        assert_eq!(table["DEBUG"].as_str(), Some(""));
+        assert_eq!(table["FEATURE_X"].as_str(), Some(""));
+        assert_eq!(table["NAME"].as_str(), Some("app"));
+
+        let options = MergeOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let result = parse_env_vars(content, "test.env", &options).err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            "Failed to parse test.env: Parse error at line 2: expected '=' after FEATURE_X"
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_bom() {
+        let patterns = vec!["src/test_data/bom/windows.env".to_owned()];
+        let env_vars = get_env_vars(&patterns, &MergeOptions::default()).unwrap();
+        assert_eq!(env_vars[0].key, "FIRST_KEY");
+        assert_eq!(env_vars[0].value, "first");
+        let config_content = merge_env_into_toml(&patterns, "").unwrap();
+        assert!(config_content.contains("\nFIRST_KEY = \"first\"\n"));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_crlf() {
+        let content = "A=1\r\nSCRIPT=\"line one\r\nline two\r\n\"\r\n";
+        let env_vars = parse_env_vars(content, "test.env", &MergeOptions::default()).unwrap();
+        let mut table = toml::value::Table::new();
+        merge_env_table(&mut table, &env_vars, &MergeOptions::default()).unwrap();
+        assert_eq!(table["SCRIPT"].as_str(), Some("line one||||line two"));
+        let config_content = toml::to_string(&table).unwrap();
+        assert!(!config_content.contains('\r'));
+        assert!(!config_content.contains("\\r"));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_no_markers() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            no_markers: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        assert!(config_content.starts_with("[env]\n"));
+        assert!(!config_content.contains("GENERATED BY"));
+
+        // Markers of a previous run are removed, and re-running changes nothing
+        let existing = std::fs::read_to_string("src/test_data/old_verify.toml").unwrap();
+        let config_content = merge_env_into_toml_with(&patterns, &existing, &options).unwrap();
+        assert!(!config_content.contains("GENERATED BY"));
+        assert!(!config_content.contains("MULTILINE DELIMITER"));
+        assert!(config_content.contains("[test]"));
+        let again = merge_env_into_toml_with(&patterns, &config_content, &options).unwrap();
+        assert_eq!(again, config_content);
+        let with_markers = merge_env_into_toml(&patterns, &config_content).unwrap();
+        assert_eq!(with_markers, existing);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_custom_markers() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            marker_start: "# BEGIN env (generated)".to_owned(),
+            marker_end: "# END env".to_owned(),
+            ..Default::default()
+        };
+        let existing = std::fs::read_to_string("src/test_data/old_verify.toml").unwrap();
+        let config_content = merge_env_into_toml_with(&patterns, &existing, &options).unwrap();
+        assert!(
+            config_content.starts_with("# BEGIN env (generated)\n# MULTILINE DELIMITER: ||||\n")
+        );
+        assert!(config_content.contains("\n# END env\n"));
+        assert!(!config_content.contains("GENERATED BY"));
+        let again = merge_env_into_toml_with(&patterns, &config_content, &options).unwrap();
+        assert_eq!(again, config_content);
+        assert_eq!(again.matches("# BEGIN env (generated)").count(), 1);
+        assert_eq!(again.matches("# END env").count(), 1);
+
+        for marker in ["BEGIN", "# two\nlines"] {
+            let options = MergeOptions {
+                marker_end: marker.to_owned(),
+                ..Default::default()
+            };
+            let result = merge_env_into_toml_with(&patterns, "", &options)
+                .err()
+                .unwrap();
+            assert_eq!(
+                result.to_string(),
+                MergeError::InvalidMarker(marker.to_owned()).to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_validate_keys() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            validate_keys: true,
+            ..Default::default()
+        };
+        assert!(merge_env_into_toml_with(&patterns, "", &options).is_ok());
+
+        let path = Path::new("src/test_data/keys/invalid.env")
+            .display()
+            .to_string();
+        let result = merge_env_into_toml_with(std::slice::from_ref(&path), "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::InvalidKey("my-key".to_owned(), path).to_string()
+        );
+
+        let path = Path::new("src/test_data/group/services.env")
+            .display()
+            .to_string();
+        let options = MergeOptions {
+            key_pattern: Regex::new("^[A-Z]+_[A-Z]+$").unwrap(),
+            ..options
+        };
+        let result = merge_env_into_toml_with(std::slice::from_ref(&path), "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::InvalidKey("_PRIVATE".to_owned(), path).to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_special_keys() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let keys = ["my.key", "my-key", "my key", "quote\"key", "a.b.c"];
+        let options = MergeOptions {
+            defaults: keys
+                .iter()
+                .map(|key| (key.to_string(), format!("value of {}", key)))
+                .collect(),
+            annotate_source: true,
+            ..Default::default()
+        };
+        let existing = "[env]\n\"my.key\" = \"old\"\n\n[other]\n\"x.y\" = 1\n";
+        let config_content = merge_env_into_toml_with(&patterns, existing, &options).unwrap();
+        assert!(config_content.contains("\n\"my.key\" = \"value of my.key\""));
+        assert!(config_content.contains("\nmy-key = \"value of my-key\""));
+        assert!(config_content.contains("\n\"my key\" = \"value of my key\""));
+
+        let config: toml::value::Table = toml::from_str(&config_content).unwrap();
+        let env = config["env"].as_table().unwrap();
+        for key in keys {
+            assert_eq!(
+                env[key].as_str(),
+                Some(format!("value of {}", key).as_str())
+            );
+        }
+        assert!(!env.contains_key("a"));
+        assert_eq!(config["other"]["x.y"], Value::Integer(1));
+        let again = merge_env_into_toml_with(&patterns, &config_content, &options).unwrap();
+        assert_eq!(again, config_content);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_separator() {
+        let content = "HOST: localhost\nURL: http://host:8080/path\n";
+        let options = MergeOptions {
+            separator: ':',
+            ..Default::default()
+        };
+        let env_vars = parse_env_vars(content, "legacy.conf", &options).unwrap();
+        let mut table = toml::value::Table::new();
+        merge_env_table(&mut table, &env_vars, &options).unwrap();
+        assert_eq!(table["HOST"].as_str(), Some("localhost"));
+        assert_eq!(table["URL"].as_str(), Some("http://host:8080/path"));
+
+        let options = MergeOptions {
+            separator: ' ',
+            ..Default::default()
+        };
+        let result = merge_env_into_toml_with(&[], "", &options).err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::InvalidSeparator(' ').to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_no_trim() {
+        let content = "SPACED=\"  spaced  \"\nBLANK=\" \"\nSCRIPT=\"  one\n  two\n\"\n";
+        let options = MergeOptions {
+            no_trim: true,
+            ..Default::default()
+        };
+        let env_vars = parse_env_vars(content, "test.env", &options).unwrap();
+        let mut table = toml::value::Table::new();
+        merge_env_table(&mut table, &env_vars, &options).unwrap();
+        assert_eq!(table["SPACED"].as_str(), Some("  spaced  "));
+        assert_eq!(table["BLANK"].as_str(), Some(" "));
+        assert_eq!(table["SCRIPT"].as_str(), Some("  one||||  two||||"));
+        let config_content = toml::to_string(&table).unwrap();
+        assert!(config_content.contains("SPACED = \"  spaced  \""));
+
+        let env_vars = parse_env_vars(content, "test.env", &MergeOptions::default()).unwrap();
+        assert_eq!(env_vars[0].value, "spaced");
+        assert_eq!(env_vars[1].value, "");
+        assert_eq!(env_vars[2].value, "one||||  two");
+    }
+
+    #[test]
+    fn test_merge_outcome() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            defaults: vec![("LOG_LEVEL".to_owned(), "info".to_owned())],
+            ..Default::default()
+        };
+        let outcome = merge(&patterns, "", &options).unwrap();
+        assert_eq!(
+            outcome.content,
+            merge_env_into_toml_with(&patterns, "", &options).unwrap()
+        );
+        let config: toml::value::Table = toml::from_str(&outcome.content).unwrap();
+        assert_eq!(outcome.section, config["env"]);
+        assert_eq!(outcome.report.summary().added, outcome.sources.len());
+        assert_eq!(outcome.sources["LOG_LEVEL"], "<default>");
+        let path = Path::new("src/test_data/1.env").display().to_string();
+        assert_eq!(outcome.sources["A"], path);
+
+        let options = MergeOptions {
+            format: OutputFormat::Json,
+            ..Default::default()
+        };
+        let outcome = merge(&patterns, "", &options).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&outcome.content).unwrap();
+        assert_eq!(json["env"], serde_json::to_value(&outcome.section).unwrap());
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_merge_strategy() {
+        let patterns = vec!["src/test_data/deep/db.env".to_owned()];
+        let existing = std::fs::read_to_string("src/test_data/deep/config.toml").unwrap();
+        let replace = MergeOptions {
+            parse_json: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, &existing, &replace).unwrap();
+        let config: toml::value::Table = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["NAME"].as_str(), Some("service"));
+        let db = config["env"]["DB"].as_table().unwrap();
+        assert_eq!(db["HOST"].as_str(), Some("db.internal"));
+        assert!(!db.contains_key("PORT"));
+        assert!(!db["POOL"].as_table().unwrap().contains_key("MAX"));
+
+        let deep = MergeOptions {
+            merge_strategy: MergeStrategy::Deep,
+            ..replace
+        };
+        let config_content = merge_env_into_toml_with(&patterns, &existing, &deep).unwrap();
+        let config: toml::value::Table = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["NAME"].as_str(), Some("service"));
+        let db = config["env"]["DB"].as_table().unwrap();
+        assert_eq!(db["HOST"].as_str(), Some("db.internal"));
+        assert_eq!(db["PORT"].as_integer(), Some(5432));
+        assert_eq!(db["POOL"]["MAX"].as_integer(), Some(10));
+        assert_eq!(db["POOL"]["MIN"].as_integer(), Some(1));
+        let again = merge_env_into_toml_with(&patterns, &config_content, &deep).unwrap();
+        assert_eq!(again, config_content);
+
+        let content = "DB='{\"POOL\": 5}'\n";
+        let env_vars = parse_env_vars(content, "test.env", &deep).unwrap();
+        let mut table: toml::value::Table = toml::from_str(&existing).unwrap();
+        let mut env_table = table.remove("env").unwrap().as_table().unwrap().clone();
+        let result = merge_env_table(&mut env_table, &env_vars, &deep)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::KeyConflict("DB".to_owned(), "DB.POOL".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn test_path_sort_key() {
+        let mut paths: Vec<PathBuf> = ["b/a.env", "B/a.env", "a/B.env", "a/b.env", "a.env"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        paths.sort_by_cached_key(|path| path_sort_key(path));
+        let paths: Vec<_> = paths.iter().map(|path| path.to_str().unwrap()).collect();
+        assert_eq!(paths, ["a.env", "a/B.env", "a/b.env", "B/a.env", "b/a.env"]);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_deterministic() {
+        // Other tests write env files below src/test_data/, only read fixtures here
+        let patterns: Vec<String> = ["[0-9]", "layered/*", "order/*", "case/*", "group/*"]
+            .iter()
+            .map(|pattern| format!("src/test_data/{}.env", pattern))
+            .collect();
+        let options = MergeOptions {
+            on_duplicate: DuplicatePolicy::LastWins,
+            annotate_source: true,
+            merge_toml: vec![PathBuf::from("src/test_data/merge_toml/layer.toml")],
+            ..Default::default()
+        };
+        let existing = std::fs::read_to_string("src/test_data/old_verify.toml").unwrap();
+        let first = merge(&patterns, &existing, &options).unwrap();
+        for _ in 0..3 {
+            let again = merge(&patterns, &existing, &options).unwrap();
+            assert_eq!(again.content.as_bytes(), first.content.as_bytes());
+            assert_eq!(again.report, first.report);
+        }
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_empty_file() {
+        let patterns = vec![
+            "src/test_data/[0-9].env".to_owned(),
+            "src/test_data/empty/*.env".to_owned(),
+        ];
+        let config_content = merge_env_into_toml(&patterns, "").unwrap();
+        let expected = merge_env_into_toml(&patterns[..1], "").unwrap();
+        assert_eq!(config_content, expected);
+
+        let options = MergeOptions {
+            error_on_empty: true,
+            ..Default::default()
+        };
+        assert!(merge_env_into_toml_with(&patterns[..1], "", &options).is_ok());
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        let path = Path::new("src/test_data/empty/blank.env").display();
+        assert_eq!(
+            result.to_string(),
+            MergeError::EmptyFile(path.to_string()).to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_namespace_by_dir() {
+        let patterns = vec!["src/test_data/services/*/db.env".to_owned()];
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        assert!(matches!(
+            result.downcast_ref::<MergeError>(),
+            Some(MergeError::DuplicateKey(..))
+        ));
+
+        let options = MergeOptions {
+            namespace_by_dir: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: toml::value::Table = toml::from_str(&config_content).unwrap();
+        let env = config["env"].as_table().unwrap();
+        assert_eq!(env["AUTH_HOST"].as_str(), Some("auth-db.internal"));
+        assert_eq!(env["BILLING_HOST"].as_str(), Some("billing-db.internal"));
+        assert!(!env.contains_key("HOST"));
+
+        let options = MergeOptions {
+            namespace_by_dir: true,
+            namespace_separator: ".".to_owned(),
+            namespace_case: KeyCase::Keep,
+            nested: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: toml::value::Table = toml::from_str(&config_content).unwrap();
+        assert_eq!(
+            config["env"]["auth"]["HOST"].as_str(),
+            Some("auth-db.internal")
+        );
+        assert_eq!(config["env"]["billing"]["PORT"].as_str(), Some("5433"));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_respect_gitignore() {
+        let folder = Path::new("src/test_data/gitignore");
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(folder.join("ignored/nested")).unwrap();
+        std::fs::create_dir_all(folder.join("local")).unwrap();
+        std::fs::write(folder.join(".gitignore"), "ignored/\n*.local.env\n").unwrap();
+        std::fs::write(folder.join("local/.gitignore"), "!kept.local.env\n").unwrap();
+        std::fs::write(folder.join("app.env"), "APP=1\n").unwrap();
+        std::fs::write(folder.join("dev.local.env"), "DEV=1\n").unwrap();
+        std::fs::write(folder.join("ignored/nested/secret.env"), "SECRET=1\n").unwrap();
+        std::fs::write(folder.join("local/kept.local.env"), "KEPT=1\n").unwrap();
+
+        let patterns = vec!["src/test_data/gitignore/**/*.env".to_owned()];
+        let keys = |options: &MergeOptions| -> Vec<String> {
+            let env_vars = get_env_vars(&patterns, options).unwrap();
+            env_vars.into_iter().map(|var| var.key).collect()
+        };
+        let all = keys(&MergeOptions::default());
+        let options = MergeOptions {
+            respect_gitignore: true,
+            ..Default::default()
+        };
+        let kept = keys(&options);
+        let _ = std::fs::remove_dir_all(folder);
+        assert_eq!(all, vec!["APP", "DEV", "KEPT", "SECRET"]);
+        assert_eq!(kept, vec!["APP", "KEPT"]);
+    }
+
+    #[test]
+    fn test_trace_sources() {
+        let patterns = vec!["src/test_data/layered/*.env".to_owned()];
+        let (base, other) = (
+            Path::new("src/test_data/layered/base.env").display(),
+            Path::new("src/test_data/layered/override.env").display(),
+        );
+        let counts = vec![(base.to_string(), 2), (other.to_string(), 2)];
+        let lines = |on_duplicate| {
+            let options = MergeOptions {
+                on_duplicate,
+                trace_sources: true,
+                ..Default::default()
+            };
+            trace_sources(&counts, &get_env_vars(&patterns, &options).unwrap())
+        };
+        assert_eq!(
+            lines(DuplicatePolicy::LastWins),
+            vec![
+                "Merge order of 2 env files:".to_owned(),
+                format!("  1. {}: 2 keys, 1 merged", base),
+                format!("  2. {}: 2 keys, 2 merged", other),
+            ]
+        );
+        assert_eq!(
+            lines(DuplicatePolicy::FirstWins)[1..],
+            [
+                format!("  1. {}: 2 keys, 2 merged", base),
+                format!("  2. {}: 2 keys, 1 merged", other),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_no_shrink() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            no_shrink: true,
+            ..Default::default()
+        };
+        assert!(merge_env_into_toml_with(&patterns, "", &options).is_ok());
+        let existing = "[env]\nA = \"old\"\n\n[other]\nX = 1\n";
+        assert!(merge_env_into_toml_with(&patterns, existing, &options).is_ok());
+
+        // The section holds keys of env files that are gone
+        let existing = std::fs::read_to_string("src/test_data/old_verify.toml").unwrap();
+        let result = merge_env_into_toml_with(&patterns, &existing, &options)
+            .err()
+            .unwrap();
+        let removed = ["E", "F", "G"].map(str::to_owned).to_vec();
+        assert_eq!(
+            result.to_string(),
+            MergeError::SectionShrinks(removed).to_string()
+        );
+        assert!(merge_env_into_toml(&patterns, &existing).is_ok());
+
+        let options = MergeOptions {
+            nested: true,
+            ..options
+        };
+        let existing = "[env.DB]\nHOST = \"x\"\n";
+        let result = merge_env_into_toml_with(&patterns, existing, &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::SectionShrinks(vec!["DB.HOST".to_owned()]).to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_replace_env() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            replace_env: true,
+            ..Default::default()
+        };
+        // old_verify.toml holds E, F and G, which the env files don't define
+        let existing = std::fs::read_to_string("src/test_data/old_verify.toml").unwrap();
+        let config_content = merge_env_into_toml(&patterns, &existing).unwrap();
+        assert!(config_content.contains("\nE = "));
+        let config_content = merge_env_into_toml_with(&patterns, &existing, &options).unwrap();
+        let config: toml::value::Table = toml::from_str(&config_content).unwrap();
+        let keys: Vec<&String> = config["env"].as_table().unwrap().keys().collect();
+        assert_eq!(keys, ["A", "B", "C"]);
+        assert_eq!(config["test"]["Y"].as_str(), Some("asd||||qwe"));
+        let again = merge_env_into_toml_with(&patterns, &config_content, &options).unwrap();
+        assert_eq!(again, config_content);
+
+        let options = MergeOptions {
+            nested: true,
+            ..options
+        };
+        let existing = "[env]\nA = \"old\"\n\n[env.DB]\nHOST = \"x\"\n";
+        let config_content = merge_env_into_toml_with(&patterns, existing, &options).unwrap();
+        let config: toml::value::Table = toml::from_str(&config_content).unwrap();
+        assert!(!config["env"].as_table().unwrap().contains_key("DB"));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_profile() {
+        let patterns = vec!["src/test_data/profiles/*.env".to_owned()];
+        let options = MergeOptions {
+            profile: Some("prod".to_owned()),
+            on_duplicate: DuplicatePolicy::LastWins,
+            ..Default::default()
+        };
+        let sources = get_env_sources(&patterns, &options).unwrap();
+        let names: Vec<String> = sources
+            .iter()
+            .map(|source| match source {
+                EnvSource::File(path) => path.file_name().unwrap().to_string_lossy().into(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(names, ["app.env", "db.env", "app.prod.env"]);
+
+        let env_vars = get_env_vars(&patterns, &options).unwrap();
+        let values: Vec<(&str, &str)> = env_vars
+            .iter()
+            .map(|var| (var.key.as_str(), var.value.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("DB_URL", "postgres://localhost/app"),
+                ("HOST", "prod.internal"),
+                ("LOG_LEVEL", "info"),
+            ]
+        );
+
+        let options = MergeOptions {
+            on_duplicate: DuplicatePolicy::Error,
+            ..options
+        };
+        let result = get_env_vars(&patterns, &options).err().unwrap();
+        assert!(matches!(
+            result.downcast_ref::<MergeError>(),
+            Some(MergeError::DuplicateKey(key, ..)) if key == "HOST"
+        ));
+        assert_eq!(file_profiles(Path::new(".env")), Vec::<&str>::new());
+        assert_eq!(
+            file_profiles(Path::new("a/.app.eu.prod.env")),
+            ["eu", "prod"]
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_final_newline() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let existing = "[test]\nY = 1\n\n\n";
+        let merged = |final_newline| {
+            let options = MergeOptions {
+                final_newline,
+                ..Default::default()
+            };
+            merge_env_into_toml_with(&patterns, existing, &options).unwrap()
+        };
+        let end = format!("{}\n", DEFAULT_MARKER_END);
+        assert!(merged(FinalNewline::Ensure).ends_with(&format!("ccc\"\n\n{}", end)));
+        assert!(merged(FinalNewline::Trim).ends_with(DEFAULT_MARKER_END));
+        assert!(merged(FinalNewline::Keep).ends_with(&format!("{}\n\n", end)));
+
+        let options = MergeOptions {
+            format: OutputFormat::Json,
+            final_newline: FinalNewline::Trim,
+            ..Default::default()
+        };
+        let content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        assert!(content.ends_with('}'));
+        assert_eq!(FinalNewline::Ensure.apply(String::new()), "\n");
+        assert_eq!(FinalNewline::Ensure.apply("a\n\n".to_owned()), "a\n");
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_output_sort() {
+        // override.env defines B and C, base.env A and B: base.env wins B as merged last
+        let files = ["override", "base"]
+            .map(|name| PathBuf::from(format!("src/test_data/layered/{}.env", name)));
+        let keys = |output_sort, existing: &str| {
+            let options = MergeOptions {
+                files: files.to_vec(),
+                on_duplicate: DuplicatePolicy::LastWins,
+                output_sort,
+                ..Default::default()
+            };
+            let config_content = merge_env_into_toml_with(&[], existing, &options).unwrap();
+            let config: toml::value::Table = toml::from_str(&config_content).unwrap();
+            let env = config["env"].as_table().unwrap();
+            assert_eq!(env["B"].as_str(), Some("2"));
+            env.keys().cloned().collect::<Vec<_>>()
+        };
+        assert_eq!(keys(None, ""), ["A", "B", "C"]);
+        assert_eq!(keys(Some(OutputSort::Alpha), ""), ["A", "B", "C"]);
+        assert_eq!(keys(Some(OutputSort::Insertion), ""), ["B", "C", "A"]);
+        assert_eq!(keys(Some(OutputSort::Unsorted), ""), ["B", "C", "A"]);
+
+        let existing = "[env]\nZ = \"kept\"\nA = \"old\"\n";
+        assert_eq!(keys(None, existing), ["A", "B", "C", "Z"]);
+        assert_eq!(
+            keys(Some(OutputSort::Insertion), existing),
+            ["B", "C", "A", "Z"]
+        );
+        assert_eq!(
+            keys(Some(OutputSort::Unsorted), existing),
+            ["Z", "A", "B", "C"]
+        );
+
+        let options = MergeOptions {
+            preserve_order: true,
+            ..Default::default()
+        };
+        assert_eq!(options.key_order(), OutputSort::Unsorted);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_timestamp() {
+        assert_eq!(
+            timestamp_comment(SystemTime::UNIX_EPOCH),
+            format!(
+                "# Generated 1970-01-01T00:00:00Z by env_to_config_toml {}",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            timestamp: true,
+            ..Default::default()
+        };
+        let existing = std::fs::read_to_string("src/test_data/old.toml").unwrap();
+        let config_content = merge_env_into_toml_with(&patterns, &existing, &options).unwrap();
+        let lines: Vec<&str> = config_content.lines().collect();
+        assert_eq!(lines[0], DEFAULT_MARKER_START);
+        assert!(is_timestamp_comment(lines[1]));
+        assert_eq!(lines[2], "# MULTILINE DELIMITER: ||||");
+
+        let expected = std::fs::read_to_string("src/test_data/old_verify.toml").unwrap();
+        assert_eq!(without_timestamp(&config_content), expected);
+        let again = merge_env_into_toml_with(&patterns, &config_content, &options).unwrap();
+        assert_eq!(again.matches("# Generated ").count(), 1);
+        assert_eq!(
+            merge_env_into_toml(&patterns, &config_content).unwrap(),
+            expected
+        );
+        let extracted = extract_env_from_toml(&config_content, &MergeOptions::default()).unwrap();
+        assert!(extracted.starts_with("A=\"# nice\nasd\nqwe\"\n"));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_invalid_pattern() {
+        let cases = [
+            (
+                "src/test_data/[unterminated",
+                "*.bak",
+                "src/test_data/[unterminated",
+            ),
+            ("*.env", "[", "["),
+        ];
+        for (pattern, exclude, invalid) in cases {
+            let options = MergeOptions {
+                exclude: vec![exclude.to_owned()],
+                ..Default::default()
+            };
+            let result = merge_env_into_toml_with(&[pattern.to_owned()], "", &options)
+                .err()
+                .unwrap();
+            assert!(matches!(
+                result.downcast_ref::<MergeError>(),
+                Some(MergeError::InvalidPattern(p, _)) if p == invalid
+            ));
+            assert!(result
+                .to_string()
+                .starts_with(&format!("Invalid glob pattern {}: ", invalid)));
+        }
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_schema() {
+        let patterns = vec!["src/test_data/schema/app.env".to_owned()];
+        let options = MergeOptions {
+            schema: Some(Schema::from_path(Path::new("src/test_data/schema/schema.toml")).unwrap()),
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: toml::value::Table = toml::from_str(&config_content).unwrap();
+        let env = config["env"].as_table().unwrap();
+        assert_eq!(env["PORT"], Value::Integer(8080));
+        assert_eq!(env["RATIO"], Value::Float(0.25));
+        assert_eq!(env["DEBUG"], Value::Boolean(false));
+        assert_eq!(env["VERSION"].as_str(), Some("1.10"));
+        let hosts: Vec<&str> = env["HOSTS"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|host| host.as_str().unwrap())
+            .collect();
+        assert_eq!(hosts, ["a.com", "b.com"]);
+        // Keys without a type are strings, even with --typed
+        assert_eq!(env["RETRIES"].as_str(), Some("3"));
+        let typed = MergeOptions {
+            typed: true,
+            ..options.clone()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, "", &typed).unwrap();
+        let config: toml::value::Table = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["RETRIES"].as_str(), Some("3"));
+        assert_eq!(config["env"]["PORT"], Value::Integer(8080));
+
+        let content = "PORT=http\n";
+        let env_vars = parse_env_vars(content, "test.env", &options).unwrap();
+        let mut table = toml::value::Table::new();
+        let result = merge_env_table(&mut table, &env_vars, &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            "Value of PORT is not a valid int: \"http\""
+        );
+        let result = parse_schema("src/test_data/schema/missing.toml")
+            .err()
+            .unwrap();
+        assert!(matches!(result, MergeError::InvalidSchema(..)));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_report_all_duplicates() {
+        let patterns = vec!["src/test_data/duplicates/*.env".to_owned()];
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        assert!(matches!(
+            result.downcast_ref::<MergeError>(),
+            Some(MergeError::DuplicateKey(key, ..)) if key == "PORT"
+        ));
+
+        let options = MergeOptions {
+            report_all_duplicates: true,
+            ..Default::default()
+        };
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        let path = |name: &str| {
+            Path::new("src/test_data/duplicates")
+                .join(name)
+                .display()
+                .to_string()
+        };
+        assert_eq!(
+            result.to_string(),
+            format!(
+                "Duplicate keys:\n  HOST in {a}, {c}\n  PORT in {a}, {b}, {c}",
+                a = path("a.env"),
+                b = path("b.env"),
+                c = path("c.env")
+            )
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_defaults_file() {
+        let folder = Path::new("src/test_data/defaults");
+        let options = MergeOptions {
+            defaults_file: Some(folder.join(".env.defaults")),
+            ..Default::default()
+        };
+        let patterns = vec!["src/test_data/defaults/*.env".to_owned()];
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["HOST"].as_str(), Some("db.internal"));
+        assert_eq!(config["env"]["PORT"].as_str(), Some("5432"));
+        assert_eq!(config["env"]["USER"].as_str(), Some("app"));
+
+        // Only the keys of the defaults file may be overridden
+        let patterns = vec![
+            "src/test_data/defaults/*.env".to_owned(),
+            "src/test_data/defaults/conflict/*.env".to_owned(),
+        ];
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::DuplicateKey(
+                "HOST".to_owned(),
+                folder.join("conflict/db.env").display().to_string(),
+                folder.join("app.env").display().to_string()
+            )
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_rename() {
+        let patterns = vec!["src/test_data/layered/*.env".to_owned()];
+        let rename = |renames: &[(&str, &str)]| MergeOptions {
+            renames: renames
+                .iter()
+                .map(|(old, new)| (old.to_string(), new.to_string()))
+                .collect(),
+            on_duplicate: DuplicatePolicy::LastWins,
+            ..Default::default()
+        };
+        let options = rename(&[("A", "FIRST"), ("C", "Z")]);
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        let keys: Vec<&String> = config["env"].as_table().unwrap().keys().collect();
+        assert_eq!(keys, ["B", "FIRST", "Z"]);
+        assert_eq!(config["env"]["FIRST"].as_str(), Some("1"));
+
+        let result = merge_env_into_toml_with(&patterns, "", &rename(&[("A", "B")]))
+            .err()
+            .unwrap();
+        assert!(matches!(
+            result.downcast_ref::<MergeError>(),
+            Some(MergeError::DuplicateKey(key, ..)) if key == "B"
+        ));
+        let result = merge_env_into_toml_with(&patterns, "", &rename(&[("A", "X"), ("C", "X")]))
+            .err()
+            .unwrap();
+        assert!(matches!(
+            result.downcast_ref::<MergeError>(),
+            Some(MergeError::DuplicateKey(key, ..)) if key == "X"
+        ));
+        assert!(parse_rename("A=").is_err());
+    }
+
+    #[test]
+    fn test_to_scalar_value_datetime() {
+        let options = MergeOptions {
+            typed: true,
+            ..Default::default()
+        };
+        for value in [
+            "2024-01-15",
+            "2024-01-15T10:30:00Z",
+            "2024-01-15T10:30:00.5+02:00",
+            "10:30:00",
+        ] {
+            let datetime = value.parse().unwrap();
+            assert_eq!(to_scalar_value(value, &options), Value::Datetime(datetime));
+        }
+        for value in [
+            "2024-01-15 release",
+            "2024-13-45",
+            "2024-1-5",
+            "v2024-01-15",
+        ] {
+            let string = Value::String(value.to_owned());
+            assert_eq!(to_scalar_value(value, &options), string);
+        }
+        assert_eq!(
+            to_scalar_value("2024-01-15", &MergeOptions::default()),
+            Value::String("2024-01-15".to_owned())
+        );
+        let table: Value = toml::from_str("A = [2024-01-15]\nB = 1\n").unwrap();
+        let expected: Value = toml::from_str("A = [\"2024-01-15\"]\nB = 1\n").unwrap();
+        assert_eq!(datetimes_as_strings(&table), expected);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_prune_empty_section() {
+        let patterns = vec!["src/test_data/1.env".to_owned()];
+        let original = "name = \"service\"\n\n[other]\nx = 1\n";
+        let existing = merge_env_into_toml(&patterns, original).unwrap();
+        let options = MergeOptions {
+            prefix_filter: Some("MISSING_".to_owned()),
+            replace_env: true,
+            ..Default::default()
+        };
+        let kept = merge_env_into_toml_with(&patterns, &existing, &options).unwrap();
+        assert!(kept.contains("[env]\n\n"));
+        assert!(kept.contains(DEFAULT_MARKER_START));
+
+        let options = MergeOptions {
+            prune_empty_section: true,
+            ..options
+        };
+        let pruned = merge_env_into_toml_with(&patterns, &existing, &options).unwrap();
+        assert_eq!(pruned, original);
+        let pruned = merge_env_into_toml_with(&patterns, original, &options).unwrap();
+        assert_eq!(pruned, original);
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_allow_keys_from() {
+        let folder = Path::new("src/test_data/allow");
+        let options = MergeOptions {
+            allow_keys_from: Some(folder.join("schema.env")),
+            ..Default::default()
+        };
+        let patterns = vec!["src/test_data/allow/secrets.env".to_owned()];
+        let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        let env = config["env"].as_table().unwrap();
+        let keys: Vec<&String> = env.keys().collect();
+        assert_eq!(keys, ["API_TOKEN", "DB_PASSWORD"]);
+        assert_eq!(env["DB_PASSWORD"].as_str(), Some("hunter2"));
+
+        let options = MergeOptions {
+            allow_keys_from: Some(folder.join("missing.env")),
+            ..Default::default()
+        };
+        assert!(merge_env_into_toml_with(&patterns, "", &options).is_err());
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_value_case() {
+        let content = "DEBUG=TRUE\nMODE=Fast\nNAME=\"  Mixed Case  \"\n";
+        let merge = |options: &MergeOptions| {
+            let env_vars = parse_env_vars(content, "test.env", options).unwrap();
+            let mut table = toml::value::Table::new();
+            merge_env_table(&mut table, &env_vars, options).unwrap();
+            table
+        };
+        let options = MergeOptions {
+            value_case: KeyCase::Lower,
+            typed: true,
+            ..Default::default()
+        };
+        let table = merge(&options);
+        assert_eq!(table["DEBUG"], Value::Boolean(true));
+        assert_eq!(table["MODE"].as_str(), Some("fast"));
+        assert_eq!(table["NAME"].as_str(), Some("mixed case"));
+
+        let table = merge(&MergeOptions {
+            value_case: KeyCase::Upper,
+            no_trim: true,
+            ..Default::default()
+        });
+        assert_eq!(table["DEBUG"].as_str(), Some("TRUE"));
+        assert_eq!(table["MODE"].as_str(), Some("FAST"));
+        assert_eq!(table["NAME"].as_str(), Some("  Mixed Case  "));
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_manifest() {
+        let folder = Path::new("src/test_data/manifest");
+        let options = MergeOptions {
+            manifest: Some(folder.join("env.list")),
+            on_duplicate: DuplicatePolicy::LastWins,
+            ..Default::default()
+        };
+        let outcome = merge(&[], "", &options).unwrap();
+        // override.env is listed before base.env, which wins
+        assert_eq!(outcome.section["B"].as_str(), Some("2"));
+        assert_eq!(
+            outcome.sources["C"],
+            folder.join("../layered/override.env").display().to_string()
+        );
+
+        let options = MergeOptions {
+            manifest: Some(folder.join("missing.list")),
+            ..Default::default()
+        };
+        let result = merge(&[], "", &options).err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::ManifestFileNotFound(
+                folder.join("missing.env").display().to_string(),
+                folder.join("missing.list").display().to_string(),
+                3
+            )
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_control_chars() {
+        let path = "src/test_data/control/control.env";
+        let patterns = vec![path.to_owned()];
+        // The serializer escapes them, reading the document back gives the same values
+        for control_chars in [ControlChars::Keep, ControlChars::Warn] {
+            let options = MergeOptions {
+                control_chars,
+                ..Default::default()
+            };
+            let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+            assert!(config_content.contains("TAB = \"a\\tb\"\n"));
+            let config: Value = toml::from_str(&config_content).unwrap();
+            assert_eq!(config["env"]["TAB"].as_str(), Some("a\tb"));
+            assert_eq!(config["env"]["NUL"].as_str(), Some("x\u{0}y"));
+        }
+
+        let options = MergeOptions {
+            control_chars: ControlChars::Error,
+            ..Default::default()
+        };
+        let result = merge_env_into_toml_with(&patterns, "", &options)
+            .err()
+            .unwrap();
+        let source = Path::new(path).display().to_string();
+        assert_eq!(
+            result.to_string(),
+            MergeError::ControlCharacter("NUL".to_owned(), source, '\0').to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_input_encoding() {
+        for (path, input_encoding) in [
+            ("src/test_data/encoding/latin1.env", InputEncoding::Latin1),
+            ("src/test_data/encoding/utf16.env", InputEncoding::Utf16),
+        ] {
+            let options = MergeOptions {
+                input_encoding,
+                ..Default::default()
+            };
+            let patterns = vec![path.to_owned()];
+            let config_content = merge_env_into_toml_with(&patterns, "", &options).unwrap();
+            let config: Value = toml::from_str(&config_content).unwrap();
+            assert_eq!(config["env"]["NAME"].as_str(), Some("Café"));
+        }
+        let patterns = vec!["src/test_data/encoding/latin1.env".to_owned()];
+        let result = merge_env_into_toml(&patterns, "").err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::InvalidEncoding(
+                Path::new(&patterns[0]).display().to_string(),
+                "utf-8".to_owned()
+            )
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_list_keys() {
+        let patterns = vec!["src/test_data/layered/*.env".to_owned()];
+        let options = MergeOptions {
+            on_duplicate: DuplicatePolicy::LastWins,
+            ..Default::default()
+        };
+        let source = |name: &str| {
+            Path::new("src/test_data/layered")
+                .join(name)
+                .display()
+                .to_string()
+        };
+        assert_eq!(
+            list_keys(&patterns, &options).unwrap(),
+            [
+                ("A".to_owned(), source("base.env")),
+                ("B".to_owned(), source("override.env")),
+                ("C".to_owned(), source("override.env")),
+            ]
+        );
+        let result = list_keys(&patterns, &MergeOptions::default())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            result.downcast_ref::<MergeError>(),
+            Some(MergeError::DuplicateKey(key, ..)) if key == "B"
+        ));
+    }
+}
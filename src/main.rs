@@ -1,216 +1,695 @@
-use clap::Parser;
-use env_file_reader::read_file;
-use glob::glob;
-use log::{debug, error, info, trace, LevelFilter};
+use clap::builder::ArgPredicate;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
+use console::{style, StyledObject};
+use env_to_config_toml::{
+    extract_env_from_toml, is_url, list_keys, merge, pattern_base, without_timestamp, MergeError,
+    MergeOptions, MergeReport, OutputFormat, STDIN_PATTERN,
+};
+use glob::Pattern;
+use log::{debug, error, info, warn, LevelFilter};
+use notify::event::ModifyKind;
+use notify::{EventKind, RecursiveMode, Watcher};
 use simple_logger::SimpleLogger;
-use std::collections::HashMap;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::ffi::OsString;
 use std::fs::{read_to_string, File};
-use std::io::{Cursor, Write};
-use std::path::PathBuf;
-use thiserror::Error;
-use toml::Value;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::mpsc::channel;
+use std::time::Duration;
 
-const END: &str = "\n# GENERATED BY ENV_TO_CONFIG_TOML END\n";
-const START: &str = "# GENERATED BY ENV_TO_CONFIG_TOML START\n";
+/// How long to wait for more file events before merging again in watch mode
+const DEBOUNCE: Duration = Duration::from_millis(300);
 
-fn main() {
-    let args = Args::parse();
+/// The config file read from the current directory when `--config` isn't given
+const DEFAULT_CONFIG: &str = "config-cli.toml";
+
+fn main() -> ExitCode {
+    let args = match with_config(std::env::args_os().collect(), Path::new(DEFAULT_CONFIG)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Reading the config file failed: {:#}", e);
+            return ExitCode::from(2);
+        }
+    };
+    let matches = command().get_matches_from(args);
+    if matches.subcommand_name().is_some() {
+        let Commands::Completions { shell } =
+            Commands::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        let name = command().get_name().to_owned();
+        clap_complete::generate(shell, &mut command(), name, &mut std::io::stdout());
+        return ExitCode::SUCCESS;
+    }
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     args.init_log();
-    match args.get_merge_bytes() {
-        Ok(bytes) => {
-            let mut file = File::create(args.get_out_path()).expect("Failed to create file");
-            file.write_all(&bytes).expect("Failed to write to file");
-            info!("Merge env files success");
+    let code = match args.run() {
+        Ok(code) => code,
+        Err(e) => {
+            error!("Merge env files failed: {:#}", e);
+            exit_code(&e)
+        }
+    };
+    if args.watch {
+        if let Err(e) = args.watch() {
+            error!("Watching env files failed: {:#}", e);
+            return exit_code(&e);
+        }
+    }
+    code
+}
+
+/// The exit code for a failed run: 3 when no env file matched or a file of `--files`
+/// or the manifest is missing, 4 for keys defined in more than one file and 2 for
+/// everything else, like IO and parse errors. Code 1 is left to `--check`.
+fn exit_code(error: &anyhow::Error) -> ExitCode {
+    match error.downcast_ref::<MergeError>() {
+        Some(
+            MergeError::NoFileFound(_)
+            | MergeError::FileNotFound(_)
+            | MergeError::ManifestFileNotFound(..),
+        ) => ExitCode::from(3),
+        Some(MergeError::DuplicateKey(..) | MergeError::DuplicateKeys(_)) => ExitCode::from(4),
+        _ => ExitCode::from(2),
+    }
+}
+
+/// The command line of [`Args`] with the subcommands added. A subcommand skips the
+/// merge entirely, so the merge arguments are not required with it.
+fn command() -> clap::Command {
+    Commands::augment_subcommands(Args::command())
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+}
+
+/// The command line `args` with the options of the config file inserted before the
+/// options given on it. The file is the one of `--config`, or `default_config` if it
+/// exists. Options on the command line are not taken from the file, so they win over
+/// it, and the file wins over the defaults.
+fn with_config(args: Vec<OsString>, default_config: &Path) -> Result<Vec<OsString>> {
+    // Required options may only be in the file, so they are not checked yet. Errors
+    // are left to the real parse
+    let Ok(matches) = command().ignore_errors(true).try_get_matches_from(&args) else {
+        return Ok(args);
+    };
+    if matches.subcommand_name().is_some() {
+        return Ok(args);
+    }
+    let path = match matches.get_one::<PathBuf>("config") {
+        Some(path) => path.clone(),
+        None if default_config.is_file() => default_config.to_path_buf(),
+        None => return Ok(args),
+    };
+    let config_args = config_args(&path, &matches)?;
+    let Some((name, rest)) = args.split_first() else {
+        return Ok(args);
+    };
+    Ok(std::iter::once(name.clone())
+        .chain(config_args.into_iter().map(OsString::from))
+        .chain(rest.iter().cloned())
+        .collect())
+}
+
+/// The options of the config file at `path` as command line arguments, except
+/// for those already in `matches` or conflicting with one of them. Keys are the long option names, with `-` or `_`.
+fn config_args(path: &Path, matches: &ArgMatches) -> Result<Vec<String>> {
+    let content =
+        read_to_string(path).with_context(|| format!("Failed to read config {:?}", path))?;
+    let config: toml::Table =
+        toml::from_str(&content).with_context(|| format!("Failed to parse config {:?}", path))?;
+    let command = command();
+    let given: Vec<&Arg> = command
+        .get_arguments()
+        .filter(|arg| matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine))
+        .collect();
+    let conflicts = |arg: &Arg, other: &Arg| {
+        let conflicts_with = |a: &Arg, b: &Arg| {
+            command
+                .get_arg_conflicts_with(a)
+                .iter()
+                .any(|conflict| conflict.get_id() == b.get_id())
+        };
+        conflicts_with(arg, other) || conflicts_with(other, arg)
+    };
+    let mut args = Vec::new();
+    for (key, value) in config {
+        let long = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+            .filter(|_| long != "config")
+            .with_context(|| format!("Unknown option {} in config {:?}", key, path))?;
+        if given
+            .iter()
+            .any(|other| other.get_id() == arg.get_id() || conflicts(arg, other))
+        {
+            continue;
+        }
+        let flag = format!("--{}", long);
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => args.push(flag.clone()),
+                toml::Value::Boolean(false) => {}
+                toml::Value::Integer(count) if matches!(arg.get_action(), ArgAction::Count) => {
+                    args.extend(std::iter::repeat_n(flag.clone(), count.max(0) as usize))
+                }
+                toml::Value::String(value) => args.push(format!("{}={}", flag, value)),
+                value => args.push(format!("{}={}", flag, value)),
+            }
         }
-        Err(e) => error!("Merge env files failed: {}", e),
     }
+    Ok(args)
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Print the completion script for a shell to stdout, nothing is merged
+    Completions { shell: Shell },
+}
+
+/// Summarize the lines only present in `old` (`-`) or `new` (`+`)
+fn diff_summary(old: &str, new: &str) -> String {
+    let old_lines: HashSet<&str> = old.lines().collect();
+    let new_lines: HashSet<&str> = new.lines().collect();
+    let removed = old
+        .lines()
+        .filter(|line| !new_lines.contains(line))
+        .map(|line| format!("- {}", line));
+    let added = new
+        .lines()
+        .filter(|line| !old_lines.contains(line))
+        .map(|line| format!("+ {}", line));
+    removed.chain(added).collect::<Vec<_>>().join("\n")
+}
+
+/// One line per changed key of the report: added in green, updated in yellow with
+/// the old and new values, removed in red. Masked values are written as `***`
+fn report_diff(report: &MergeReport, options: &MergeOptions) -> Vec<StyledObject<String>> {
+    let value =
+        |key: &str, value: &toml::Value| options.log_value(key, &value.to_string()).to_owned();
+    let added = report.added.iter().map(|added| {
+        style(format!(
+            "+ {} = {}",
+            added.key,
+            value(&added.key, &added.value)
+        ))
+        .green()
+    });
+    let updated = report.updated.iter().map(|updated| {
+        style(format!(
+            "~ {} = {} -> {}",
+            updated.key,
+            value(&updated.key, &updated.old),
+            value(&updated.key, &updated.new)
+        ))
+        .yellow()
+    });
+    let removed = report.removed.iter().map(|removed| {
+        style(format!(
+            "- {} = {}",
+            removed.key,
+            value(&removed.key, &removed.value)
+        ))
+        .red()
+    });
+    added.chain(updated).chain(removed).collect()
+}
+
+/// Copy `path` to `path.bak`, returning the backup path
+fn backup_file(path: &Path) -> Result<PathBuf> {
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(".bak");
+    let backup_path = PathBuf::from(backup_path);
+    std::fs::copy(path, &backup_path)
+        .with_context(|| format!("Failed to back up {:?} to {:?}", path, backup_path))?;
+    Ok(backup_path)
 }
 
-#[derive(Error, Debug)]
-pub enum MergeError {
-    #[error("Duplicate key: {0} in {1} and {2}")]
-    DuplicateKey(String, String, String),
-    #[error("No file found for the pattern: {0}")]
-    NoFileFound(String),
+/// Write `bytes` to a temporary file next to `out_path`, then rename it over
+/// `out_path` so readers never see a partially written file. A symlinked `out_path`
+/// is written through, and the permissions of an existing file are kept.
+fn write_atomically(out_path: &Path, bytes: &[u8]) -> Result<()> {
+    let resolved = match out_path.exists() {
+        true => Some(
+            std::fs::canonicalize(out_path)
+                .with_context(|| format!("Failed to resolve {:?}", out_path))?,
+        ),
+        false => None,
+    };
+    let out_path = resolved.as_deref().unwrap_or(out_path);
+    let permissions = std::fs::metadata(out_path)
+        .ok()
+        .map(|metadata| metadata.permissions());
+    let file_name = out_path
+        .file_name()
+        .with_context(|| format!("Invalid out path {:?}", out_path))?;
+    let tmp_path = out_path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    let write = || -> Result<()> {
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create file {:?}", tmp_path))?;
+        file.write_all(bytes)
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("Failed to write to file {:?}", tmp_path))?;
+        if let Some(permissions) = &permissions {
+            std::fs::set_permissions(&tmp_path, permissions.clone())
+                .with_context(|| format!("Failed to set the permissions of {:?}", tmp_path))?;
+        }
+        std::fs::rename(&tmp_path, out_path)
+            .with_context(|| format!("Failed to rename {:?} to {:?}", tmp_path, out_path))?;
+        Ok(())
+    };
+    let result = write();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
 }
 
 /// Merge multiple .env files into one
 #[derive(Parser, Debug)]
 #[command(author, about, long_about = None)]
 struct Args {
-    /// The directory containing .env files
-    #[arg(short, long)]
-    pattern: String,
+    /// Glob pattern of the .env files to merge, may be repeated. `-` reads from
+    /// stdin, after all matched files. http:// and https:// URLs are fetched with
+    /// the `http` feature
+    #[arg(short, long, required_unless_present_any = ["files", "manifest", "extract"])]
+    pattern: Vec<String>,
 
-    /// The output file to write the merged .env file to
-    #[arg(short, long)]
+    /// The output file to write the merged .env file to, `-` for stdout
+    #[arg(
+        short,
+        long,
+        required = false,
+        required_unless_present = "list_keys",
+        default_value_if("list_keys", ArgPredicate::IsPresent, "-")
+    )]
     out_path: PathBuf,
 
-    /// Debug log level
+    #[command(flatten)]
+    merge: MergeOptions,
+
+    /// Print the merged output to stdout instead of writing it
     #[arg(long)]
-    v: bool,
-    /// Trace log level
+    dry_run: bool,
+
+    /// Exit with code 1 if the output file is not up to date, without writing it
+    #[arg(long, conflicts_with = "dry_run")]
+    check: bool,
+
+    /// Copy an existing output file to `<out_path>.bak` before overwriting it
+    #[arg(long)]
+    backup: bool,
+
+    /// Keep running and merge again whenever a matched env file changes, or a file of
+    /// `--defaults-file`, `--merge-toml` or `--allow-keys-from`
+    #[arg(long, conflicts_with = "check")]
+    watch: bool,
+
+    /// Print the changes to the section before writing, in color on a terminal unless
+    /// `NO_COLOR` is set. With `--dry-run` only the changes are printed
+    #[arg(long)]
+    diff: bool,
+
+    /// Write a JSON report of the added, updated, unchanged and removed keys to this path.
+    /// With `--dry-run` or `--check` it is printed to stderr instead
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    /// Instead of merging, write the section of the output file back as a .env
+    /// file to this path, `-` for stdout
+    #[arg(long, conflicts_with_all = ["check", "dry_run", "watch"])]
+    extract: Option<PathBuf>,
+
+    /// Instead of merging, print the keys of the env files sorted, with their source
+    /// under `-v`. Duplicate keys fail like for a merge, the output file is not touched
+    #[arg(
+        long,
+        conflicts_with_all = ["check", "dry_run", "watch", "backup", "diff", "report", "extract"]
+    )]
+    list_keys: bool,
+
+    /// Read default options from this TOML file, keyed by their long names.
+    /// `config-cli.toml` is read from the current directory if it exists. Options on
+    /// the command line win over the file, which wins over the defaults
     #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Increase the log level from info, -v for debug, -vv for trace
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Only log errors
+    #[arg(short, long, conflicts_with_all = ["verbose", "log_level", "v", "vvvv"])]
+    quiet: bool,
+
+    /// Deprecated, use -v instead of `v` and -vv instead of `vvvv`
+    #[arg(short, long, hide = true, value_parser = ["v", "vvvv"], conflicts_with = "verbose")]
+    log_level: Option<String>,
+    /// Deprecated, use -v instead
+    #[arg(long, hide = true)]
+    v: bool,
+    /// Deprecated, use -vv instead
+    #[arg(long, hide = true)]
     vvvv: bool,
 }
 
 impl Args {
     pub fn init_log(&self) {
-        let log_level = if self.vvvv {
-            LevelFilter::Trace
-        } else if self.v {
-            LevelFilter::Debug
-        } else {
-            LevelFilter::Info
-        };
-        SimpleLogger::new().with_level(log_level).init().unwrap();
+        SimpleLogger::new()
+            .with_level(self.log_level())
+            .init()
+            .unwrap();
+        if self.v || self.vvvv || self.log_level.is_some() {
+            warn!("--log-level, --v and --vvvv are deprecated, use -v and -vv instead");
+        }
+        if self.merge.follow_symlinks {
+            warn!("--follow-symlinks is deprecated, symlinks are followed by default");
+        }
     }
 
-    pub fn get_out_path(&self) -> &PathBuf {
-        &self.out_path
+    pub fn log_level(&self) -> LevelFilter {
+        if self.quiet {
+            return LevelFilter::Error;
+        }
+        if self.vvvv || self.log_level.as_deref() == Some("vvvv") {
+            return LevelFilter::Trace;
+        }
+        if self.v || self.log_level.is_some() {
+            return LevelFilter::Debug;
+        }
+        match self.verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
     }
 
-    pub fn get_merge_bytes(&self) -> Result<Vec<u8>> {
-        let env_vars = self.get_env_vars()?;
-
-        return match self.out_path.exists() {
-            true => {
-                debug!("Merging into existing file: {:?}", self.out_path);
-
-                let file_content = read_to_string(self.out_path.clone())?;
-                let result = self.merge_existing_toml(&env_vars, &file_content)?;
-                Ok(result)
+    pub fn run(&self) -> Result<ExitCode> {
+        if self.list_keys {
+            return self.list_keys();
+        }
+        if let Some(target) = &self.extract {
+            return self.extract(target);
+        }
+        let bytes = self.get_merge_bytes()?;
+        if self.check {
+            return self.check(&bytes);
+        }
+        if self.dry_run {
+            if !self.diff {
+                std::io::stdout()
+                    .write_all(&bytes)
+                    .context("Failed to write to stdout")?;
             }
+            info!("Dry run, nothing written to {:?}", self.out_path);
+            return Ok(ExitCode::SUCCESS);
+        }
+        if self.is_stdout() {
+            std::io::stdout()
+                .write_all(&bytes)
+                .context("Failed to write to stdout")?;
+        } else {
+            if self.backup && self.out_path.exists() {
+                let backup_path = backup_file(&self.out_path)?;
+                info!("Backed up {:?} to {:?}", self.out_path, backup_path);
+            }
+            write_atomically(&self.out_path, &bytes)?;
+        }
+        info!("Merge env files success");
+        Ok(ExitCode::SUCCESS)
+    }
+
+    fn check(&self, bytes: &[u8]) -> Result<ExitCode> {
+        let current = match !self.is_stdout() && self.out_path.exists() {
+            true => std::fs::read(&self.out_path)?,
             false => {
-                debug!("Creating new file in: {:?}", self.out_path);
-                let parent = self
-                    .out_path
-                    .parent()
-                    .expect("Failed to get parent directory");
-                std::fs::create_dir_all(parent)?;
-                let result = self.merge_existing_toml(&env_vars, "")?;
-                Ok(result)
+                error!("{:?} does not exist", self.out_path);
+                return Ok(ExitCode::from(1));
             }
         };
+        // The --timestamp comment changes on every run
+        let current = without_timestamp(&String::from_utf8_lossy(&current));
+        let merged = without_timestamp(&String::from_utf8_lossy(bytes));
+        if current == merged {
+            info!("{:?} is up to date", self.out_path);
+            return Ok(ExitCode::SUCCESS);
+        }
+        error!("{:?} is not up to date", self.out_path);
+        println!("{}", diff_summary(&current, &merged));
+        Ok(ExitCode::from(1))
     }
 
-    fn get_env_vars(&self) -> Result<Vec<(String, String)>> {
-        let mut env_paths: Vec<PathBuf> = glob(&self.pattern)
-            .expect("Failed to read glob pattern")
-            .filter_map(Result::ok)
-            .filter(|path| path.is_file())
-            .collect();
-        if env_paths.is_empty() {
-            return Err(MergeError::NoFileFound(self.pattern.clone()).into());
+    fn list_keys(&self) -> Result<ExitCode> {
+        let mut stdout = std::io::stdout().lock();
+        for (key, source) in list_keys(&self.pattern, &self.merge)? {
+            match self.verbose {
+                0 => writeln!(stdout, "{}", key),
+                _ => writeln!(stdout, "{}\t{}", key, source),
+            }
+            .context("Failed to write to stdout")?;
         }
+        Ok(ExitCode::SUCCESS)
+    }
 
-        env_paths.sort_by_key(|path| path.to_str().unwrap().to_lowercase());
-        let mut env_vars = HashMap::new();
-        let mut env_paths_by_key = HashMap::new();
-        for env_path in env_paths {
-            info!("Reading env file: {:?}", env_path);
-            let env = read_file(env_path.clone())?;
-            for (key, value) in env {
-                let value = value
-                    .trim()
-                    .lines()
-                    .filter(|s| !s.starts_with("#"))
-                    .collect::<Vec<_>>()
-                    .join("||||");
-                if env_vars.contains_key(&key) {
-                    let duplicate_path: &PathBuf = env_paths_by_key.get(&key).unwrap();
-                    return Err(MergeError::DuplicateKey(
-                        key,
-                        env_path.display().to_string(),
-                        duplicate_path.display().to_string(),
-                    )
-                    .into());
-                }
-                env_vars.insert(key.clone(), value);
-                env_paths_by_key.insert(key, env_path.clone());
+    fn extract(&self, target: &Path) -> Result<ExitCode> {
+        let file_content = read_to_string(&self.out_path)
+            .with_context(|| format!("Failed to read {:?}", self.out_path))?;
+        let content = extract_env_from_toml(&file_content, &self.merge)?;
+        if target == Path::new("-") {
+            std::io::stdout()
+                .write_all(content.as_bytes())
+                .context("Failed to write to stdout")?;
+        } else {
+            write_atomically(target, content.as_bytes())?;
+        }
+        info!("Extracted [{}] to {:?}", self.merge.section, target);
+        Ok(ExitCode::SUCCESS)
+    }
+
+    /// Merge again on every burst of changes to the env files and the other input
+    /// files, until killed
+    fn watch(&self) -> Result<()> {
+        if self.pattern.iter().any(|pattern| pattern == STDIN_PATTERN) {
+            anyhow::bail!("Can't watch stdin");
+        }
+        let (sender, receiver) = channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        for dir in self.watch_dirs() {
+            info!("Watching {:?}", dir);
+            watcher
+                .watch(&dir, RecursiveMode::Recursive)
+                .with_context(|| format!("Failed to watch {:?}", dir))?;
+        }
+        loop {
+            let event = receiver.recv()??;
+            let changed = match event.kind {
+                EventKind::Create(_) | EventKind::Remove(_) => true,
+                EventKind::Modify(kind) => !matches!(kind, ModifyKind::Metadata(_)),
+                _ => false,
+            };
+            if !changed || !event.paths.iter().any(|path| self.is_watched(path)) {
+                continue;
+            }
+            debug!("Env files changed: {:?}", event.paths);
+            while receiver.recv_timeout(DEBOUNCE).is_ok() {}
+            info!("Env files changed, merging again");
+            if let Err(e) = self.run() {
+                error!("Merge env files failed: {:#}", e);
             }
         }
-        let mut env_vars: Vec<_> = env_vars
+    }
+
+    /// The files read besides the pattern matches: those of `--files`, the
+    /// `--manifest`, `--defaults-file`, `--merge-toml` and `--allow-keys-from`
+    fn input_files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.merge
+            .files
             .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect();
-        env_vars.sort_by_key(|(key, _)| key.to_lowercase());
-        Ok(env_vars)
-    }
-
-    fn merge_existing_toml(
-        &self,
-        env_vars: &[(String, String)],
-        file_content: &str,
-    ) -> Result<Vec<u8>> {
-        let mut config: toml::Value = toml::from_str(file_content)?;
-        let table = config.as_table_mut().unwrap();
-
-        let env_table = table
-            .entry("env".to_owned())
-            .or_insert_with(|| {
-                debug!("Creating new [env] section");
-                toml::Value::Table(toml::value::Table::new())
-            })
-            .as_table_mut()
-            .unwrap();
+            .filter(|file| !file.to_str().is_some_and(is_url))
+            .chain(&self.merge.manifest)
+            .chain(&self.merge.defaults_file)
+            .chain(&self.merge.merge_toml)
+            .chain(&self.merge.allow_keys_from)
+    }
 
-        for (key, value) in env_vars {
-            if env_table.contains_key(key) {
-                debug!("Updating env var: {}={}", key, value);
-                trace!("Old value: {:?}", env_table.get(key));
-            } else {
-                debug!("Adding env var: {}={}", key, value);
+    /// The directories holding the files matched by the patterns and the
+    /// [`Args::input_files`]
+    fn watch_dirs(&self) -> Vec<PathBuf> {
+        let paths = self
+            .pattern
+            .iter()
+            .filter(|pattern| *pattern != STDIN_PATTERN && !is_url(pattern))
+            .map(|pattern| pattern_base(pattern))
+            .chain(self.input_files().cloned());
+        let mut dirs = Vec::new();
+        for path in paths {
+            let dir = match path.is_dir() {
+                true => path,
+                false => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            };
+            let dir = match dir.as_os_str().is_empty() {
+                true => PathBuf::from("."),
+                false => dir,
+            };
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
             }
-            env_table.insert(key.to_owned(), Value::String(value.to_owned()));
         }
-        let env_table_len = env_table.len();
-        let content = self.add_prefix(&config, env_table_len);
+        dirs
+    }
 
-        let mut writer = Cursor::new(Vec::new());
-        writer.write_all(content.as_bytes())?;
-        Ok(writer.into_inner())
+    /// Whether a change to `path` should trigger a new merge
+    fn is_watched(&self, path: &Path) -> bool {
+        let current_dir = std::env::current_dir().unwrap_or_default();
+        let relative = path.strip_prefix(&current_dir).unwrap_or(path);
+        if relative == self.out_path || path == self.out_path {
+            return false;
+        }
+        let candidates = [
+            path.to_path_buf(),
+            relative.to_path_buf(),
+            Path::new(".").join(relative),
+        ];
+        let matches_pattern = self
+            .pattern
+            .iter()
+            .filter_map(|pattern| Pattern::new(pattern).ok())
+            .any(|pattern| candidates.iter().any(|path| pattern.matches_path(path)));
+        matches_pattern || self.input_files().any(|file| candidates.contains(file))
     }
 
-    fn add_prefix(&self, value: &Value, len: usize) -> String {
-        let env_section_index = {
-            let config_table = value.as_table().unwrap();
-            let mut index = 0;
-            for (key, _) in config_table.iter() {
-                if key == "env" {
-                    break;
-                }
-                index += 1;
+    pub fn is_stdout(&self) -> bool {
+        self.out_path == Path::new("-")
+    }
+
+    /// Fail if the out path has the extension of another output format
+    fn check_format(&self) -> Result<()> {
+        let format = self.merge.format;
+        let other = self
+            .out_path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(OutputFormat::from_extension)
+            .filter(|other| *other != format);
+        match other {
+            Some(_) => Err(MergeError::FormatMismatch(
+                self.out_path.display().to_string(),
+                format!("{:?}", format).to_lowercase(),
+            )
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Merge into the output file if it exists, creating its directory otherwise
+    /// unless nothing is written
+    pub fn get_merge_bytes(&self) -> Result<Vec<u8>> {
+        self.check_format()?;
+        let existing = match !self.is_stdout() && self.out_path.exists() {
+            true => {
+                debug!("Merging into existing file: {:?}", self.out_path);
+                Some(read_to_string(&self.out_path)?)
             }
-            index
+            false => None,
         };
-        let toml_str = toml::to_string_pretty(&value).expect("Failed to serialize TOML value");
-        let mut lines: Vec<&str> = toml_str.lines().collect();
-        lines.insert(env_section_index, START);
-        lines.insert(env_section_index + len + 2, END);
-        let toml_str = lines.join("\n");
-        toml_str
+        let bytes = self.merge_bytes(existing.as_deref())?;
+        if existing.is_none() && !self.dry_run && !self.check && !self.is_stdout() {
+            debug!("Creating new file in: {:?}", self.out_path);
+            let parent = self
+                .out_path
+                .parent()
+                .expect("Failed to get parent directory");
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(bytes)
+    }
+
+    /// Merge the env files into `existing`, or into a new document for `None`.
+    /// Independent of the out path, only the report is written, and printed to stderr
+    /// instead with `--dry-run` or `--check`.
+    pub fn merge_bytes(&self, existing: Option<&str>) -> Result<Vec<u8>> {
+        let outcome = merge(&self.pattern, existing.unwrap_or_default(), &self.merge)?;
+        info!("{}", outcome.report.summary());
+        if self.diff {
+            // The merged document goes to stdout with `-o -`
+            for line in report_diff(&outcome.report, &self.merge) {
+                match self.is_stdout() {
+                    true => eprintln!("{}", line.for_stderr()),
+                    false => println!("{}", line),
+                }
+            }
+        }
+        if let Some(report_path) = &self.report {
+            let json = serde_json::to_string_pretty(&outcome.report)? + "\n";
+            if self.dry_run || self.check {
+                eprint!("{}", json);
+            } else {
+                write_atomically(report_path, json.as_bytes())?;
+                debug!("Wrote the merge report to {:?}", report_path);
+            }
+        }
+        Ok(outcome.content.into_bytes())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use env_to_config_toml::DuplicatePolicy;
+    use glob::glob;
     use std::path::{Path, PathBuf};
 
+    fn args(pattern: &str, out: &Path) -> Args {
+        Args::parse_from([
+            "env-to-config-toml",
+            "--pattern",
+            pattern,
+            "--out-path",
+            out.to_str().unwrap(),
+        ])
+    }
+
+    #[test]
+    fn test_completions() {
+        let matches = command()
+            .try_get_matches_from(["env-to-config-toml", "completions", "bash"])
+            .unwrap();
+        assert!(matches!(
+            Commands::from_arg_matches(&matches).unwrap(),
+            Commands::Completions { shell: Shell::Bash }
+        ));
+        let mut script = Vec::new();
+        clap_complete::generate(
+            Shell::Bash,
+            &mut command(),
+            "env-to-config-toml",
+            &mut script,
+        );
+        assert!(String::from_utf8(script).unwrap().contains("--out-path"));
+        // Without a subcommand the merge arguments are still required
+        assert!(command()
+            .try_get_matches_from(["env-to-config-toml", "--pattern", "*.env"])
+            .is_err());
+    }
+
     #[test]
     fn test_merge_env_files_new() {
         let out = Path::new("src/test_data/new_config.toml");
         let pattern = "src/test_data/[0-9].env";
         let _ = std::fs::remove_file(out);
-        let args = Args {
-            pattern: pattern.to_owned(),
-            out_path: out.to_owned(),
-            v: false,
-            vvvv: false,
-        };
+        let args = args(pattern, out);
 
         let bytes = args.get_merge_bytes().unwrap();
         let config_content = String::from_utf8(bytes).unwrap();
@@ -226,12 +705,7 @@ mod tests {
         let _ = std::fs::remove_dir(new_folder);
         assert!(!new_folder.exists());
         let pattern = "src/test_data/[0-9].env";
-        let args = Args {
-            pattern: pattern.to_owned(),
-            out_path: out.to_owned(),
-            v: false,
-            vvvv: false,
-        };
+        let args = args(pattern, out);
 
         let bytes = args.get_merge_bytes().unwrap();
         let config_content = String::from_utf8(bytes).unwrap();
@@ -245,12 +719,7 @@ mod tests {
         let pattern = "src/test_data/[0-9].env";
         let out = Path::new("src/test_data/exist_config.toml");
         let _ = std::fs::copy("src/test_data/old.toml", out).unwrap();
-        let args = Args {
-            pattern: pattern.to_owned(),
-            out_path: out.to_owned(),
-            v: false,
-            vvvv: false,
-        };
+        let args = args(pattern, out);
 
         let bytes = args.get_merge_bytes().unwrap();
         let config_content = String::from_utf8(bytes).unwrap();
@@ -258,17 +727,24 @@ mod tests {
         assert_eq!(config_content, verify_content);
     }
 
+    #[test]
+    fn test_merge_bytes() {
+        let out = Path::new("src/test_data/missing/merge_bytes.toml");
+        let args = args("src/test_data/[0-9].env", out);
+        let new_verify = std::fs::read_to_string("src/test_data/new_verify.toml").unwrap();
+        assert_eq!(args.merge_bytes(None).unwrap(), new_verify.as_bytes());
+        let old = std::fs::read_to_string("src/test_data/old.toml").unwrap();
+        let old_verify = std::fs::read_to_string("src/test_data/old_verify.toml").unwrap();
+        assert_eq!(args.merge_bytes(Some(&old)).unwrap(), old_verify.as_bytes());
+        assert!(!out.parent().unwrap().exists());
+    }
+
     #[test]
     fn test_merge_env_files_overwrite() {
         let out = Path::new("src/test_data/overwrite_config.toml");
         let _ = std::fs::copy("src/test_data/overwrite.toml", out).unwrap();
         let pattern = "src/test_data/[0-9].env";
-        let args = Args {
-            pattern: pattern.to_owned(),
-            out_path: out.to_owned(),
-            v: false,
-            vvvv: false,
-        };
+        let args = args(pattern, out);
         let bytes = args.get_merge_bytes().unwrap();
         let config_content = String::from_utf8(bytes).unwrap();
         let verify_content =
@@ -281,12 +757,7 @@ mod tests {
         let out = Path::new("src/test_data/duplicate_config.toml");
         let _ = std::fs::copy("src/test_data/overwrite.toml", out).unwrap();
         let pattern = "src/test_data/*.env";
-        let args = Args {
-            pattern: pattern.to_owned(),
-            out_path: out.to_owned(),
-            v: false,
-            vvvv: false,
-        };
+        let args = args(pattern, out);
         let env_paths: Vec<PathBuf> = glob("src/test_data/duplicate.env")
             .expect("Failed to read glob pattern")
             .filter_map(Result::ok)
@@ -317,16 +788,394 @@ mod tests {
         let out = Path::new("src/test_data/duplicate_config.toml");
         let _ = std::fs::copy("src/test_data/overwrite.toml", out).unwrap();
         let pattern = "src/test_data/";
-        let args = Args {
-            pattern: pattern.to_owned(),
-            out_path: out.to_owned(),
-            v: false,
-            vvvv: false,
-        };
+        let args = args(pattern, out);
         let result = args.get_merge_bytes().err().unwrap();
         assert_eq!(
             result.to_string(),
             MergeError::NoFileFound(pattern.to_owned()).to_string()
         );
     }
+
+    #[test]
+    fn test_exit_code() {
+        let out = Path::new("src/test_data/exit_code_config.toml");
+        let _ = std::fs::remove_file(out);
+        let code = |pattern: &str| exit_code(&args(pattern, out).run().err().unwrap());
+        assert_eq!(code("src/test_data/"), ExitCode::from(3));
+        assert_eq!(code("src/test_data/*.env"), ExitCode::from(4));
+        assert_eq!(code("src/test_data/malformed/*.env"), ExitCode::from(2));
+        let missing = Args::parse_from([
+            "env-to-config-toml",
+            "--files",
+            "src/test_data/missing.env",
+            "--out-path",
+            out.to_str().unwrap(),
+        ]);
+        assert_eq!(exit_code(&missing.run().err().unwrap()), ExitCode::from(3));
+        let io_error = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!(exit_code(&io_error), ExitCode::from(2));
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn test_merge_env_files_report() {
+        let out = Path::new("src/test_data/report_config.toml");
+        let report_path = Path::new("src/test_data/report.json");
+        let _ = std::fs::copy("src/test_data/overwrite.toml", out).unwrap();
+        let args = Args::parse_from([
+            "env-to-config-toml",
+            "--pattern",
+            "src/test_data/[0-9].env",
+            "--out-path",
+            out.to_str().unwrap(),
+            "--report",
+            report_path.to_str().unwrap(),
+        ]);
+        args.get_merge_bytes().unwrap();
+        let report: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(report_path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(out);
+        let _ = std::fs::remove_file(report_path);
+        let updated = report["updated"].as_array().unwrap();
+        let a = updated.iter().find(|entry| entry["key"] == "A").unwrap();
+        assert_eq!(a["old"], "11||||qwe");
+        assert_eq!(a["new"], "# nice||||asd||||qwe");
+        assert!(report["added"].is_array());
+        assert!(report["unchanged"].is_array());
+    }
+
+    #[test]
+    fn test_merge_env_files_dry_run() {
+        let out = Path::new("src/test_data/dry_run/dry_run_config.toml");
+        let dry_run_folder = Path::new("src/test_data/dry_run");
+        let _ = std::fs::remove_dir_all(dry_run_folder);
+        let pattern = "src/test_data/[0-9].env";
+        let mut args = args(pattern, out);
+        args.dry_run = true;
+        args.report = Some(dry_run_folder.join("report.json"));
+
+        let bytes = args.get_merge_bytes().unwrap();
+        let config_content = String::from_utf8(bytes).unwrap();
+        let verify_content = std::fs::read_to_string("src/test_data/new_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+        assert!(!dry_run_folder.exists());
+    }
+
+    #[test]
+    fn test_merge_env_files_stdout() {
+        let pattern = "src/test_data/[0-9].env";
+        let args = args(pattern, Path::new("-"));
+        assert!(args.is_stdout());
+
+        let bytes = args.get_merge_bytes().unwrap();
+        let config_content = String::from_utf8(bytes).unwrap();
+        let verify_content = std::fs::read_to_string("src/test_data/new_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+    }
+
+    #[test]
+    fn test_merge_env_files_format_mismatch() {
+        let pattern = "src/test_data/[0-9].env";
+        let mut args = args(pattern, Path::new("src/test_data/json/old.json"));
+        let result = args.get_merge_bytes().err().unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::FormatMismatch("src/test_data/json/old.json".to_owned(), "toml".to_owned())
+                .to_string()
+        );
+
+        args.merge.format = OutputFormat::Json;
+        let bytes = args.get_merge_bytes().unwrap();
+        let config_content = String::from_utf8(bytes).unwrap();
+        let verify_content = std::fs::read_to_string("src/test_data/json/old_verify.json").unwrap();
+        assert_eq!(config_content, verify_content);
+    }
+
+    #[test]
+    fn test_watch_paths() {
+        let pattern = "src/test_data/**/*.env";
+        let watched = args(pattern, Path::new("src/test_data/watch.env"));
+        assert_eq!(watched.watch_dirs(), vec![PathBuf::from("src/test_data")]);
+        assert!(watched.is_watched(Path::new("src/test_data/1.env")));
+        assert!(watched.is_watched(
+            &std::env::current_dir()
+                .unwrap()
+                .join("src/test_data/a/b.env")
+        ));
+        assert!(!watched.is_watched(Path::new("src/test_data/old.toml")));
+        // The output is never watched, even when it matches the pattern
+        assert!(!watched.is_watched(Path::new("src/test_data/watch.env")));
+
+        let current = args("*.env", Path::new("out.toml"));
+        assert_eq!(current.watch_dirs(), vec![PathBuf::from(".")]);
+
+        // Files merged through other options are watched too
+        let mut inputs = args("src/test_data/[0-9].env", Path::new("out.toml"));
+        inputs.merge.defaults_file = Some(PathBuf::from("src/test_data/defaults/.env.defaults"));
+        inputs.merge.merge_toml = vec![PathBuf::from("src/test_data/merge_toml/layer.toml")];
+        inputs.merge.allow_keys_from = Some(PathBuf::from("src/test_data/allow/schema.env"));
+        assert_eq!(
+            inputs.watch_dirs(),
+            vec![
+                PathBuf::from("src/test_data"),
+                PathBuf::from("src/test_data/defaults"),
+                PathBuf::from("src/test_data/merge_toml"),
+                PathBuf::from("src/test_data/allow"),
+            ]
+        );
+        assert!(inputs.is_watched(Path::new("src/test_data/merge_toml/layer.toml")));
+        assert!(inputs.is_watched(Path::new("src/test_data/allow/schema.env")));
+        assert!(!inputs.is_watched(Path::new("src/test_data/merge_toml/other.toml")));
+    }
+
+    #[test]
+    fn test_follow_symlinks_alias() {
+        let parse = |flags: &[&str]| {
+            let mut args = vec!["env-to-config-toml", "-p", "*.env", "-o", "-"];
+            args.extend_from_slice(flags);
+            Args::try_parse_from(args)
+        };
+        let args = parse(&["--follow-symlinks"]).unwrap();
+        assert!(!args.merge.no_follow_symlinks);
+        assert!(parse(&["--follow-symlinks", "--no-follow-symlinks"]).is_err());
+    }
+
+    #[test]
+    fn test_log_level() {
+        let pattern = "src/test_data/[0-9].env";
+        let log_level = |flags: &[&str]| {
+            let mut args = vec!["env-to-config-toml", "-p", pattern, "-o", "-"];
+            args.extend_from_slice(flags);
+            Args::parse_from(args).log_level()
+        };
+        assert_eq!(log_level(&[]), LevelFilter::Info);
+        assert_eq!(log_level(&["-v"]), LevelFilter::Debug);
+        assert_eq!(log_level(&["-vv"]), LevelFilter::Trace);
+        assert_eq!(log_level(&["-v", "-v", "-v"]), LevelFilter::Trace);
+        assert_eq!(log_level(&["--v"]), LevelFilter::Debug);
+        assert_eq!(log_level(&["--vvvv"]), LevelFilter::Trace);
+        assert_eq!(log_level(&["--log-level", "v"]), LevelFilter::Debug);
+        assert_eq!(log_level(&["-l", "vvvv"]), LevelFilter::Trace);
+        assert_eq!(log_level(&["-q"]), LevelFilter::Error);
+        assert!(
+            Args::try_parse_from(["env-to-config-toml", "-p", pattern, "-o", "-", "-q", "-v"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let folder = Path::new("src/test_data/atomic");
+        let out = folder.join("atomic_config.toml");
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(folder).unwrap();
+        std::fs::write(&out, "old").unwrap();
+
+        write_atomically(&out, b"new").unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "new");
+        assert_eq!(std::fs::read_dir(folder).unwrap().count(), 1);
+
+        let missing = folder.join("missing/atomic_config.toml");
+        let result = write_atomically(&missing, b"new").err().unwrap();
+        assert!(result.to_string().starts_with("Failed to create file"));
+        let _ = std::fs::remove_dir_all(folder);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomically_keeps_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+        let folder = Path::new("src/test_data/atomic_metadata");
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(folder.join("shared")).unwrap();
+        let out = folder.join("secret.toml");
+        std::fs::write(&out, "old").unwrap();
+        std::fs::set_permissions(&out, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        write_atomically(&out, b"new").unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "new");
+        let mode = std::fs::metadata(&out).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let target = folder.join("shared/config.toml");
+        std::fs::write(&target, "old").unwrap();
+        let link = folder.join("config.toml");
+        std::os::unix::fs::symlink("shared/config.toml", &link).unwrap();
+        write_atomically(&link, b"new").unwrap();
+        assert!(std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "new");
+        assert_eq!(std::fs::read_dir(folder.join("shared")).unwrap().count(), 1);
+        let _ = std::fs::remove_dir_all(folder);
+    }
+
+    #[test]
+    fn test_merge_env_files_backup() {
+        let folder = Path::new("src/test_data/backup");
+        let out = folder.join("backup_config.toml");
+        let _ = std::fs::remove_dir_all(folder);
+        std::fs::create_dir_all(folder).unwrap();
+        std::fs::copy("src/test_data/old.toml", &out).unwrap();
+        let mut args = args("src/test_data/[0-9].env", &out);
+        args.backup = true;
+
+        args.run().unwrap();
+        let backup_content =
+            std::fs::read_to_string(folder.join("backup_config.toml.bak")).unwrap();
+        let old_content = std::fs::read_to_string("src/test_data/old.toml").unwrap();
+        assert_eq!(backup_content, old_content);
+        let config_content = std::fs::read_to_string(&out).unwrap();
+        let verify_content = std::fs::read_to_string("src/test_data/old_verify.toml").unwrap();
+        assert_eq!(config_content, verify_content);
+        let _ = std::fs::remove_dir_all(folder);
+    }
+
+    #[test]
+    fn test_merge_env_files_check() {
+        let folder = Path::new("src/test_data/check");
+        let out = folder.join("check_config.toml");
+        let _ = std::fs::remove_dir_all(folder);
+        let mut args = args("src/test_data/[0-9].env", &out);
+        args.check = true;
+        args.report = Some(folder.join("report.json"));
+        assert_eq!(args.run().unwrap(), ExitCode::from(1));
+        assert!(!folder.exists());
+
+        std::fs::create_dir_all(folder).unwrap();
+        std::fs::copy("src/test_data/old.toml", &out).unwrap();
+        assert_eq!(args.run().unwrap(), ExitCode::from(1));
+        let config_content = std::fs::read_to_string(&out).unwrap();
+        let old_content = std::fs::read_to_string("src/test_data/old.toml").unwrap();
+        assert_eq!(config_content, old_content);
+
+        std::fs::copy("src/test_data/old_verify.toml", &out).unwrap();
+        assert_eq!(args.run().unwrap(), ExitCode::SUCCESS);
+
+        // A timestamp written by an earlier run doesn't make the file outdated
+        args.check = false;
+        args.merge.timestamp = true;
+        args.run().unwrap();
+        let written = std::fs::read_to_string(&out).unwrap();
+        std::fs::write(&out, written.replace("# Generated ", "# Generated 1999 ")).unwrap();
+        args.check = true;
+        assert_eq!(args.run().unwrap(), ExitCode::SUCCESS);
+        let _ = std::fs::remove_dir_all(folder);
+    }
+
+    #[test]
+    fn test_diff_summary() {
+        assert_eq!(diff_summary("a\nb\nc", "a\nc\nd"), "- b\n+ d");
+        assert_eq!(diff_summary("a", "a"), "");
+    }
+
+    #[test]
+    fn test_list_keys_args() {
+        let args = Args::parse_from(["env-to-config-toml", "-p", "*.env", "--list-keys"]);
+        assert!(args.list_keys);
+        assert_eq!(args.out_path, Path::new("-"));
+        let result = Args::command().try_get_matches_from([
+            "env-to-config-toml",
+            "-p",
+            "*.env",
+            "--list-keys",
+            "--dry-run",
+        ]);
+        assert!(result.is_err());
+        assert!(Args::command()
+            .try_get_matches_from(["env-to-config-toml", "-p", "*.env"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_report_diff() {
+        let options = MergeOptions {
+            replace_env: true,
+            mask: true,
+            ..Default::default()
+        };
+        let existing = "[env]\nA = \"old\"\nAPI_KEY = \"secret\"\nZ = \"1\"\n";
+        let patterns = ["src/test_data/1.env".to_owned()];
+        let outcome = merge(&patterns, existing, &options).unwrap();
+        let lines: Vec<String> = report_diff(&outcome.report, &options)
+            .iter()
+            .map(|line| console::strip_ansi_codes(&line.to_string()).into_owned())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "+ B = \"# nice||||asd||||qwe||||# kind||||asd||||qaaa||||ccc\"",
+                "~ A = \"old\" -> \"# nice||||asd||||qwe\"",
+                "- API_KEY = ***",
+                "- Z = \"1\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config = Path::new("src/test_data/cli/config-cli.toml");
+        let parse = |args: &[&str], default_config: &Path| {
+            let args = args.iter().map(OsString::from).collect();
+            let args = with_config(args, default_config).unwrap();
+            Args::from_arg_matches(&command().try_get_matches_from(args).unwrap()).unwrap()
+        };
+        let missing = Path::new("src/test_data/cli/missing.toml");
+        let args = parse(
+            &[
+                "env-to-config-toml",
+                "--config",
+                "src/test_data/cli/config-cli.toml",
+            ],
+            missing,
+        );
+        assert_eq!(args.pattern, vec!["src/test_data/[0-9].env"]);
+        assert_eq!(args.out_path, Path::new("src/test_data/cli/config.toml"));
+        assert_eq!(args.merge.section, "app");
+        assert_eq!(args.merge.on_duplicate, DuplicatePolicy::LastWins);
+        assert!(args.merge.typed);
+        assert_eq!(args.verbose, 2);
+        assert_eq!(args.merge.renames, vec![("A".to_owned(), "B".to_owned())]);
+        assert_eq!(
+            args.merge.defaults,
+            vec![
+                ("PORT".to_owned(), "80".to_owned()),
+                ("HOST".to_owned(), "localhost".to_owned())
+            ]
+        );
+
+        // Options conflicting with the command line are not taken from the file
+        let conflicting = Path::new("src/test_data/cli/conflicts.toml");
+        let args = parse(
+            &["env-to-config-toml", "--files", "src/test_data/1.env"],
+            conflicting,
+        );
+        assert!(args.pattern.is_empty());
+        assert_eq!(args.merge.files, vec![Path::new("src/test_data/1.env")]);
+        assert!(args.dry_run);
+        let args = parse(&["env-to-config-toml", "--check"], conflicting);
+        assert!(args.check);
+        assert!(!args.dry_run);
+        assert_eq!(args.pattern, vec!["src/test_data/[0-9].env"]);
+
+        // The file is found without --config, and the command line wins over it
+        let args = parse(&["env-to-config-toml", "--section", "other", "-v"], config);
+        assert_eq!(args.merge.section, "other");
+        assert_eq!(args.verbose, 1);
+        assert_eq!(args.out_path, Path::new("src/test_data/cli/config.toml"));
+
+        let args = ["env-to-config-toml", "-p", "*.env", "-o", "out.toml"];
+        let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        assert_eq!(with_config(args.clone(), missing).unwrap(), args);
+
+        let unknown = Path::new("src/test_data/cli/unknown.toml");
+        std::fs::write(unknown, "sections = \"app\"\n").unwrap();
+        let result = with_config(args, unknown);
+        let _ = std::fs::remove_file(unknown);
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            format!("Unknown option sections in config {:?}", unknown)
+        );
+    }
 }
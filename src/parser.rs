@@ -0,0 +1,501 @@
+//! A small `.env` parser that keeps the order of the variables and whether
+//! their values were quoted.
+//!
+//! Supported syntax: `KEY=value`, an optional `export` keyword, values quoted
+//! with `"`, `'` or `` ` `` (which may span multiple lines), empty values and
+//! `#` comment lines. A `#` after whitespace following an unquoted value is part
+//! of it, unless [`ParseOptions`] strips inline comments. In double quotes `\"`
+//! and `\\` escape a quote and a backslash, `\t`, `\r` and `\uXXXX` give control
+//! characters, and `\n` gives a line break in any quotes.
+//! A key without `=` is read as an empty value, unless parsing strictly. The
+//! separator between keys and values may be changed with [`ParseOptions`], only its
+//! first occurrence on a line splits.
+use std::iter::Peekable;
+use std::str::Chars;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+#[error("Parse error at line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// A single `KEY=value` entry of an env file
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvEntry {
+    pub key: String,
+    pub value: String,
+    /// Whether the value was wrapped in quotes
+    pub quoted: bool,
+    /// The line the entry starts at, 1-based
+    pub line: usize,
+    /// The comment lines between the previous entry and this one, with their `#`
+    pub comments: Vec<String>,
+}
+
+/// Parse the content of an env file, keeping the entries in file order.
+pub fn parse_env_str(content: &str) -> Result<Vec<EnvEntry>, ParseError> {
+    parse_env_str_with(content, &ParseOptions::default())
+}
+
+/// Same as [`parse_env_str`], with explicit [`ParseOptions`].
+pub fn parse_env_str_with(
+    content: &str,
+    options: &ParseOptions,
+) -> Result<Vec<EnvEntry>, ParseError> {
+    Parser::new(&normalize(content), options).parse()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseOptions {
+    /// Fail on keys without a separator instead of reading them as empty values
+    pub strict: bool,
+    /// The separator between keys and values
+    pub separator: char,
+    /// Cut unquoted values at a `#` after whitespace instead of keeping the comment
+    pub strip_inline_comments: bool,
+    /// Read unquoted values starting with `{` or `[` up to the end of the line, so
+    /// JSON needs no quotes
+    pub json_values: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            separator: '=',
+            strip_inline_comments: false,
+            json_values: false,
+        }
+    }
+}
+
+/// Remove the byte order mark Windows editors may save, and turn `\r\n` and lone
+/// `\r` line endings into `\n`
+fn normalize(content: &str) -> String {
+    let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+    content.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    strict: bool,
+    separator: char,
+    strip_inline_comments: bool,
+    json_values: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn new(content: &'a str, options: &ParseOptions) -> Self {
+        Self {
+            chars: content.chars().peekable(),
+            line: 1,
+            strict: options.strict,
+            separator: options.separator,
+            strip_inline_comments: options.strip_inline_comments,
+            json_values: options.json_values,
+        }
+    }
+
+    fn parse(mut self) -> Result<Vec<EnvEntry>, ParseError> {
+        let mut entries = Vec::new();
+        let mut comments = Vec::new();
+        loop {
+            self.skip_while(char::is_whitespace);
+            match self.chars.peek() {
+                None => break,
+                Some('#') => comments.push(self.read_while(|c| c != '\n')),
+                Some(_) => {
+                    let mut entry = self.parse_entry()?;
+                    entry.comments = std::mem::take(&mut comments);
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn parse_entry(&mut self) -> Result<EnvEntry, ParseError> {
+        let line = self.line;
+        let separator = self.separator;
+        let mut key = self.read_ident(Some(separator));
+        if key == "export" && self.skip_inline_whitespace() && self.chars.peek() != Some(&separator)
+        {
+            key = self.read_ident(Some(separator));
+        }
+        if key.is_empty() {
+            return Err(self.error("expected a key"));
+        }
+        self.skip_inline_whitespace();
+        if !self.strict && matches!(self.chars.peek(), None | Some('\n') | Some('#')) {
+            self.skip_while(|c| c != '\n');
+            return Ok(EnvEntry {
+                key,
+                value: String::new(),
+                quoted: false,
+                line,
+                comments: Vec::new(),
+            });
+        }
+        if self.chars.next_if_eq(&separator).is_none() {
+            return Err(self.error(&format!("expected '{}' after {}", separator, key)));
+        }
+        let spaced = self.skip_inline_whitespace();
+        let (mut value, quoted) = match self.chars.peek() {
+            Some(&quote) if matches!(quote, '"' | '\'' | '`') => (self.read_quoted(quote)?, true),
+            Some('#') if spaced => (String::new(), false),
+            Some('{' | '[') if self.json_values => {
+                let value = self.read_while(|c| c != '\n');
+                (value.trim_end().to_owned(), false)
+            }
+            _ => (self.read_ident(None), false),
+        };
+        let spacing = self.read_while(|c| c.is_whitespace() && c != '\n');
+        // Unquoted values only reach a `#` after whitespace, it is kept unless stripping
+        if self.chars.peek() == Some(&'#') {
+            let comment = self.read_while(|c| c != '\n');
+            if !quoted && !self.strip_inline_comments {
+                value = format!("{}{}{}", value, spacing, comment.trim_end());
+            }
+        }
+        match self.chars.peek() {
+            None | Some('\n') => Ok(EnvEntry {
+                key,
+                value,
+                quoted,
+                line,
+                comments: Vec::new(),
+            }),
+            Some(&c) => Err(self.error(&format!("unexpected character {:?} after value", c))),
+        }
+    }
+
+    /// Read up to whitespace or a quote, and for keys up to a comment or the separator
+    fn read_ident(&mut self, separator: Option<char>) -> String {
+        let stops = |c: char| match separator {
+            Some(separator) => c == separator || c == '#',
+            None => false,
+        };
+        let mut ident = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|&c| !c.is_whitespace() && !matches!(c, '"' | '\'' | '`') && !stops(c))
+        {
+            ident.push(c);
+        }
+        ident
+    }
+
+    fn read_quoted(&mut self, quote: char) -> Result<String, ParseError> {
+        let line = self.line;
+        self.chars.next();
+        let mut value = String::new();
+        loop {
+            match self.chars.next() {
+                None => {
+                    return Err(ParseError {
+                        line,
+                        message: format!("unterminated {} quote", quote),
+                    })
+                }
+                Some(c) if c == quote => break,
+                Some('\\') if quote == '"' && matches!(self.chars.peek(), Some('"' | '\\')) => {
+                    value.push(self.chars.next().unwrap());
+                }
+                Some('\\') if self.chars.peek() == Some(&'n') => {
+                    self.chars.next();
+                    value.push('\n');
+                }
+                Some('\\') if quote == '"' && matches!(self.chars.peek(), Some('t' | 'r')) => {
+                    match self.chars.next() {
+                        Some('t') => value.push('\t'),
+                        _ => value.push('\r'),
+                    }
+                }
+                Some('\\') if quote == '"' && self.chars.peek() == Some(&'u') => {
+                    match self.read_unicode_escape() {
+                        Some(c) => value.push(c),
+                        None => value.push('\\'),
+                    }
+                }
+                Some(c) => {
+                    if c == '\n' {
+                        self.line += 1;
+                    }
+                    value.push(c);
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    /// Read the characters matching the predicate, which must not match line breaks
+    fn read_while(&mut self, predicate: impl Fn(char) -> bool) -> String {
+        let mut read = String::new();
+        while let Some(c) = self.chars.next_if(|&c| predicate(c)) {
+            read.push(c);
+        }
+        read
+    }
+
+    /// The character of a `\uXXXX` escape after its backslash, nothing is read when the
+    /// four hex digits are missing
+    fn read_unicode_escape(&mut self) -> Option<char> {
+        let mut ahead = self.chars.clone();
+        ahead.next();
+        let hex: String = ahead.by_ref().take(4).collect();
+        if hex.len() != 4 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let c = char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?;
+        self.chars = ahead;
+        Some(c)
+    }
+
+    /// Skip spaces and tabs, returns whether anything was skipped
+    fn skip_inline_whitespace(&mut self) -> bool {
+        let mut skipped = false;
+        while self
+            .chars
+            .next_if(|&c| c.is_whitespace() && c != '\n')
+            .is_some()
+        {
+            skipped = true;
+        }
+        skipped
+    }
+
+    fn skip_while(&mut self, predicate: impl Fn(char) -> bool) {
+        while let Some(c) = self.chars.next_if(|&c| predicate(c)) {
+            if c == '\n' {
+                self.line += 1;
+            }
+        }
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        ParseError {
+            line: self.line,
+            message: message.to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(content: &str) -> Vec<(String, String, bool)> {
+        parse_env_str(content)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.key, entry.value, entry.quoted))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_keeps_order_and_quoting() {
+        let content = "# comment\nB=2\nexport A = \"1\" # trailing\n\nexportC='x\ny'\nD=\n";
+        assert_eq!(
+            pairs(content),
+            vec![
+                ("B".to_owned(), "2".to_owned(), false),
+                ("A".to_owned(), "1".to_owned(), true),
+                ("exportC".to_owned(), "x\ny".to_owned(), true),
+                ("D".to_owned(), "".to_owned(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_export() {
+        let content = "export A=1\nB=2\nexportFOO=3\nexport\tC=4\nexport = 5\n";
+        assert_eq!(
+            pairs(content),
+            vec![
+                ("A".to_owned(), "1".to_owned(), false),
+                ("B".to_owned(), "2".to_owned(), false),
+                ("exportFOO".to_owned(), "3".to_owned(), false),
+                ("C".to_owned(), "4".to_owned(), false),
+                ("export".to_owned(), "5".to_owned(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_empty_values() {
+        let content = "DEBUG=\nFEATURE_X\nFEATURE_Y # enabled\nQUOTED=\"\"\n";
+        let empty = |key: &str, quoted| (key.to_owned(), String::new(), quoted);
+        let strict = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            pairs(content),
+            vec![
+                empty("DEBUG", false),
+                empty("FEATURE_X", false),
+                empty("FEATURE_Y", false),
+                empty("QUOTED", true),
+            ]
+        );
+        assert_eq!(
+            parse_env_str_with(content, &strict).err().unwrap(),
+            ParseError {
+                line: 2,
+                message: "expected '=' after FEATURE_X".to_owned()
+            }
+        );
+        assert_eq!(parse_env_str_with("DEBUG=\n", &strict).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_parse_strips_bom() {
+        assert_eq!(
+            pairs("\u{feff}FIRST_KEY=1\nB=\u{feff}"),
+            vec![
+                ("FIRST_KEY".to_owned(), "1".to_owned(), false),
+                ("B".to_owned(), "\u{feff}".to_owned(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_line_endings() {
+        let content = "A=1\r\nB=\"x\r\ny\"\r\n# comment\r\nC=3\rD=4";
+        assert_eq!(
+            pairs(content),
+            vec![
+                ("A".to_owned(), "1".to_owned(), false),
+                ("B".to_owned(), "x\ny".to_owned(), true),
+                ("C".to_owned(), "3".to_owned(), false),
+                ("D".to_owned(), "4".to_owned(), false),
+            ]
+        );
+        assert_eq!(parse_env_str("A=1\r\nB 2").err().unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_parse_separator() {
+        let colon = ParseOptions {
+            separator: ':',
+            ..Default::default()
+        };
+        let content = "HOST: localhost\nURL:http://host:80/\nexport NAME : \"a: b\"\nX=1\n";
+        let entries: Vec<_> = parse_env_str_with(content, &colon)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.key, entry.value))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("HOST".to_owned(), "localhost".to_owned()),
+                ("URL".to_owned(), "http://host:80/".to_owned()),
+                ("NAME".to_owned(), "a: b".to_owned()),
+                ("X=1".to_owned(), "".to_owned()),
+            ]
+        );
+        assert_eq!(
+            pairs("TOKEN=abc==\n"),
+            vec![("TOKEN".to_owned(), "abc==".to_owned(), false)]
+        );
+    }
+
+    #[test]
+    fn test_parse_inline_comments() {
+        let content = "PORT=8080 # default port\nCOLOR=\"#ff0000\" # red\nTAG='a # b'\n\
+                       EMPTY= # none\nURL=http://x/#/p\nHASH=#1\n";
+        let strip = ParseOptions {
+            strip_inline_comments: true,
+            ..Default::default()
+        };
+        let entries: Vec<_> = parse_env_str_with(content, &strip)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.key, entry.value, entry.quoted))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("PORT".to_owned(), "8080".to_owned(), false),
+                ("COLOR".to_owned(), "#ff0000".to_owned(), true),
+                ("TAG".to_owned(), "a # b".to_owned(), true),
+                ("EMPTY".to_owned(), "".to_owned(), false),
+                ("URL".to_owned(), "http://x/#/p".to_owned(), false),
+                ("HASH".to_owned(), "#1".to_owned(), false),
+            ]
+        );
+        // Without stripping the comments of unquoted values are part of them
+        assert_eq!(
+            pairs(content),
+            vec![
+                ("PORT".to_owned(), "8080 # default port".to_owned(), false),
+                ("COLOR".to_owned(), "#ff0000".to_owned(), true),
+                ("TAG".to_owned(), "a # b".to_owned(), true),
+                ("EMPTY".to_owned(), "# none".to_owned(), false),
+                ("URL".to_owned(), "http://x/#/p".to_owned(), false),
+                ("HASH".to_owned(), "#1".to_owned(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_escapes() {
+        let content = r#"A="say \"hi\"\nbye"
+B="C:\\new\\"
+C='C:\\x'
+D="a\tb\u0000\u00e9\u12""#;
+        assert_eq!(
+            pairs(content),
+            vec![
+                ("A".to_owned(), "say \"hi\"\nbye".to_owned(), true),
+                ("B".to_owned(), "C:\\new\\".to_owned(), true),
+                ("C".to_owned(), "C:\\\\x".to_owned(), true),
+                ("D".to_owned(), "a\tb\0\u{e9}\\u12".to_owned(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_comments() {
+        let content = "# first\n#second\nA=1 # inline\n\nB=2\n  # third\nC=3\n# trailing\n";
+        let comments: Vec<_> = parse_env_str(content)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.key, entry.comments))
+            .collect();
+        assert_eq!(
+            comments,
+            vec![
+                (
+                    "A".to_owned(),
+                    vec!["# first".to_owned(), "#second".to_owned()]
+                ),
+                ("B".to_owned(), vec![]),
+                ("C".to_owned(), vec!["# third".to_owned()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse_env_str("A=1\nB 2").err().unwrap(),
+            ParseError {
+                line: 2,
+                message: "expected '=' after B".to_owned()
+            }
+        );
+        assert_eq!(
+            parse_env_str("A=1\nB=\"2\n").err().unwrap(),
+            ParseError {
+                line: 2,
+                message: "unterminated \" quote".to_owned()
+            }
+        );
+        assert_eq!(parse_env_str("A=1 2").err().unwrap().line, 1);
+    }
+}
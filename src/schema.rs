@@ -0,0 +1,128 @@
+//! Value types of keys, read from the file of `--schema`
+use crate::MergeError;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use toml::Value;
+
+/// The type of the values of a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum SchemaType {
+    #[serde(rename = "int")]
+    Int,
+    #[serde(rename = "float")]
+    Float,
+    #[serde(rename = "bool")]
+    Bool,
+    #[serde(rename = "string")]
+    String,
+    /// Split on the `--array-delimiter`, `,` by default
+    #[serde(rename = "array<string>")]
+    StringArray,
+}
+
+impl SchemaType {
+    /// The name of the type in a schema file
+    pub fn name(&self) -> &'static str {
+        match self {
+            SchemaType::Int => "int",
+            SchemaType::Float => "float",
+            SchemaType::Bool => "bool",
+            SchemaType::String => "string",
+            SchemaType::StringArray => "array<string>",
+        }
+    }
+
+    /// Convert `value` to this type, `None` if it isn't a valid one
+    pub fn convert(&self, value: &str, array_delimiter: &str) -> Option<Value> {
+        Some(match self {
+            SchemaType::Int => Value::Integer(value.parse().ok()?),
+            SchemaType::Float => Value::Float(value.parse().ok().filter(|f: &f64| f.is_finite())?),
+            SchemaType::Bool => Value::Boolean(value.parse().ok()?),
+            SchemaType::String => Value::String(value.to_owned()),
+            SchemaType::StringArray => Value::Array(
+                value
+                    .split(array_delimiter)
+                    .filter(|element| !element.is_empty())
+                    .map(|element| Value::String(element.to_owned()))
+                    .collect(),
+            ),
+        })
+    }
+}
+
+/// The types of keys, like `PORT = "int"`. Keys without a type are converted as
+/// without a schema
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Schema(HashMap<String, SchemaType>);
+
+impl Schema {
+    /// Read a schema from a TOML file, or a JSON file for the `.json` extension
+    pub fn from_path(path: &Path) -> Result<Self, MergeError> {
+        let invalid =
+            |message: String| MergeError::InvalidSchema(path.display().to_string(), message);
+        let content = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        match path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            true => serde_json::from_str(&content).map_err(|e| invalid(e.to_string())),
+            false => toml::from_str(&content).map_err(|e| invalid(e.to_string())),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<SchemaType> {
+        self.0.get(key).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_from_path() {
+        for path in [
+            "src/test_data/schema/schema.toml",
+            "src/test_data/schema/schema.json",
+        ] {
+            let schema = Schema::from_path(Path::new(path)).unwrap();
+            assert_eq!(schema.get("PORT"), Some(SchemaType::Int));
+            assert_eq!(schema.get("HOSTS"), Some(SchemaType::StringArray));
+            assert_eq!(schema.get("OTHER"), None);
+        }
+        let result = toml::from_str::<Schema>("PORT = \"integer\"\n");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_schema_type_convert() {
+        assert_eq!(
+            SchemaType::Int.convert("8080", ","),
+            Some(Value::Integer(8080))
+        );
+        assert_eq!(SchemaType::Int.convert("80.5", ","), None);
+        assert_eq!(
+            SchemaType::Float.convert("0.5", ","),
+            Some(Value::Float(0.5))
+        );
+        assert_eq!(SchemaType::Float.convert("inf", ","), None);
+        assert_eq!(
+            SchemaType::Bool.convert("true", ","),
+            Some(Value::Boolean(true))
+        );
+        assert_eq!(SchemaType::Bool.convert("yes", ","), None);
+        assert_eq!(
+            SchemaType::String.convert("123", ","),
+            Some(Value::String("123".to_owned()))
+        );
+        assert_eq!(
+            SchemaType::StringArray.convert("a;;b", ";"),
+            Some(Value::Array(vec![
+                Value::String("a".to_owned()),
+                Value::String("b".to_owned())
+            ]))
+        );
+    }
+}
@@ -0,0 +1,393 @@
+//! TOML output. The existing document is edited with `toml_edit` so that only the
+//! section is rewritten, everything else keeps its comments, formatting and order.
+use crate::{
+    is_timestamp_comment, key_path, merge_env_table, order_section, timestamp_comment, EnvVar,
+    MergeError, MergeOptions, MergeReport, DEFAULT_MARKER_END, DEFAULT_MARKER_START,
+};
+use anyhow::Result;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::time::SystemTime;
+use toml::Value;
+use toml_edit::{Document, Item, Table};
+
+pub(crate) fn merge_existing_toml(
+    env_vars: &[EnvVar],
+    file_content: &str,
+    options: &MergeOptions,
+) -> Result<(String, Value, MergeReport)> {
+    let invalid = |message: String| MergeError::InvalidExisting("TOML".to_owned(), message);
+    let mut document: Document = file_content
+        .parse()
+        .map_err(|e: toml_edit::TomlError| invalid(e.to_string()))?;
+    let mut config: toml::value::Table =
+        toml::from_str(file_content).map_err(|e| invalid(e.to_string()))?;
+    let resolved;
+    let env_vars = match options.resolve_refs {
+        true => {
+            resolved = resolve_refs(env_vars, &config)?;
+            &resolved
+        }
+        false => env_vars,
+    };
+
+    let path = options.section_path();
+    let (name, parents) = path.split_last().unwrap();
+    let mut parent = &mut config;
+    for (i, key) in parents.iter().enumerate() {
+        let table = parent
+            .entry(key.to_string())
+            .or_insert_with(|| Value::Table(toml::value::Table::new()));
+        parent = match table {
+            Value::Table(table) => table,
+            _ => return Err(MergeError::SectionTypeConflict(path[..=i].join(".")).into()),
+        };
+    }
+    let mut env_table = match parent.remove(*name) {
+        None => {
+            debug!("Creating new [{}] section", options.section);
+            toml::value::Table::new()
+        }
+        Some(Value::Table(table)) => table,
+        Some(value) if options.force_section => {
+            warn!(
+                "Replacing {} = {} with a new [{}] section",
+                options.section, value, options.section
+            );
+            toml::value::Table::new()
+        }
+        Some(_) => return Err(MergeError::SectionTypeConflict(options.section.clone()).into()),
+    };
+
+    let report = merge_env_table(&mut env_table, env_vars, options)?;
+    if env_table.is_empty() && options.prune_empty_section {
+        debug!("Removing the empty [{}] section", options.section);
+        remove_section(&mut document, options);
+        return Ok((document.to_string(), Value::Table(env_table), report));
+    }
+    let mut env_table = Value::Table(env_table);
+    order_section(&mut env_table, env_vars, options);
+    let section = render_section(env_table.clone(), env_vars, options);
+    replace_section(&mut document, section, options);
+    Ok((document.to_string(), env_table, report))
+}
+
+/// The env vars with their `@path` values replaced by the value at the dotted path
+/// of the existing document
+fn resolve_refs(env_vars: &[EnvVar], config: &toml::value::Table) -> Result<Vec<EnvVar>> {
+    let mut env_vars = env_vars.to_vec();
+    for var in env_vars.iter_mut().filter(|var| !var.quoted) {
+        let Some(path) = var.value.strip_prefix('@') else {
+            continue;
+        };
+        let mut keys = path.split('.');
+        let mut value = keys.next().and_then(|key| config.get(key));
+        for key in keys {
+            value = value
+                .and_then(Value::as_table)
+                .and_then(|table| table.get(key));
+        }
+        let Some(value) = value else {
+            return Err(MergeError::UnresolvedRef(var.key.clone(), path.to_owned()).into());
+        };
+        debug!("Resolved {} to @{}", var.key, path);
+        var.typed_value = Some(value.clone());
+    }
+    Ok(env_vars)
+}
+
+/// Serialize the section on its own, as an item ready to be put in the document
+fn render_section(env_table: Value, env_vars: &[EnvVar], options: &MergeOptions) -> Item {
+    let path = options.section_path();
+    let wrapper = path.iter().rev().fold(env_table, |table, key| {
+        Value::Table(toml::value::Table::from_iter([(key.to_string(), table)]))
+    });
+    let rendered = toml::to_string_pretty(&wrapper).expect("Failed to serialize TOML value");
+    let mut rendered: Document = rendered.parse().expect("Failed to parse serialized TOML");
+    let mut section = rendered.remove(path[0]).unwrap();
+    for key in &path[1..] {
+        section = section.as_table_mut().unwrap().remove(key).unwrap();
+    }
+    if options.annotate_source {
+        let sources: HashMap<String, &str> = env_vars
+            .iter()
+            .map(|var| (key_path(&var.key, options).join("."), var.source.as_str()))
+            .collect();
+        annotate_sources(section.as_table_mut().unwrap(), "", &sources);
+    }
+    section
+}
+
+/// Add a `# from <source>` comment after each value whose key is found in `sources`
+fn annotate_sources(table: &mut Table, prefix: &str, sources: &HashMap<String, &str>) {
+    for (key, item) in table.iter_mut() {
+        let path = format!("{}{}", prefix, key.get());
+        match item {
+            Item::Table(table) => annotate_sources(table, &format!("{}.", path), sources),
+            Item::Value(value) => {
+                if let Some(source) = sources.get(&path) {
+                    value.decor_mut().set_suffix(format!("  # from {}", source));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Remove the section and the markers of previous runs in front of tables and keys
+/// and at the end of the document. Returns the position of the removed section and of the visible tables left
+fn remove_section(document: &mut Document, options: &MergeOptions) -> (Option<usize>, Vec<usize>) {
+    let path = options.section_path();
+    let (name, parents) = path.split_last().unwrap();
+    let old_position = match parent_table(document.as_table_mut(), parents).remove(name) {
+        Some(Item::Table(table)) => table.position(),
+        _ => None,
+    };
+    // Markers of previous runs may sit in front of any table or top-level key
+    let root = document.as_table_mut();
+    let keys: Vec<String> = root.iter().map(|(key, _)| key.to_owned()).collect();
+    for key in keys {
+        let decor = root.key_decor_mut(&key).unwrap();
+        if let Some(prefix) = decor.prefix().and_then(|prefix| prefix.as_str()) {
+            let prefix = strip_markers(prefix, options);
+            decor.set_prefix(prefix);
+        }
+    }
+    let mut positions = Vec::new();
+    for_each_table(root, &mut |table| {
+        if let Some(prefix) = table.decor().prefix().and_then(|prefix| prefix.as_str()) {
+            let prefix = strip_markers(prefix, options);
+            table.decor_mut().set_prefix(prefix);
+        }
+        if let Some(position) = table.position().filter(|_| is_visible(table)) {
+            positions.push(position);
+        }
+    });
+    let trailing = strip_markers(document.trailing().as_str().unwrap_or(""), options);
+    document.set_trailing(trailing);
+    (old_position, positions)
+}
+
+/// Put the rendered section in place of the existing one, wrapped in the START/END markers
+/// unless `--no-markers`. A new section goes after the last table of the document.
+fn replace_section(document: &mut Document, mut section: Item, options: &MergeOptions) {
+    let path = options.section_path();
+    let (name, parents) = path.split_last().unwrap();
+    let (mut old_position, mut positions) = remove_section(document, options);
+    let root = document.as_table_mut();
+    if options.sort_tables {
+        // Sorted with the others to find its place, the section is put back below
+        parent_table(root, parents).insert(name, section.clone());
+        sort_table(root, &path, &mut 0);
+        old_position = parent_table(root, parents)
+            .remove(name)
+            .and_then(|section| section.as_table()?.position());
+        positions.clear();
+        for_each_table(root, &mut |table| {
+            if let Some(position) = table.position().filter(|_| is_visible(table)) {
+                positions.push(position);
+            }
+        });
+    }
+    let position = old_position.unwrap_or_else(|| positions.iter().max().map_or(0, |max| max + 1));
+    let is_first = root.get_values().is_empty() && positions.iter().all(|&p| p > position);
+    let next_position = positions.iter().filter(|&&p| p > position).min().copied();
+
+    let table = section.as_table_mut().unwrap();
+    table.set_position(position);
+    for_each_table(table, &mut |table| table.set_position(position));
+    let mut prefix = if is_first { "" } else { "\n" }.to_owned();
+    if !options.no_markers {
+        prefix += &format!("{}\n", options.marker_start);
+    }
+    if options.timestamp {
+        prefix += &format!("{}\n", timestamp_comment(SystemTime::now()));
+    }
+    if !options.no_markers {
+        if !options.multiline_strings {
+            prefix += &format!("# MULTILINE DELIMITER: {}\n", options.multiline_delimiter);
+        }
+        prefix += "\n";
+    }
+    table.decor_mut().set_prefix(prefix);
+    parent_table(root, parents).insert(name, section);
+
+    match next_position {
+        _ if options.no_markers => {}
+        Some(next_position) => {
+            let mut found = false;
+            for_each_table(document.as_table_mut(), &mut |table| {
+                if found || table.position() != Some(next_position) || !is_visible(table) {
+                    return;
+                }
+                found = true;
+                let prefix = table
+                    .decor()
+                    .prefix()
+                    .and_then(|prefix| prefix.as_str())
+                    .unwrap_or("\n")
+                    .to_owned();
+                table
+                    .decor_mut()
+                    .set_prefix(format!("\n{}\n{}", options.marker_end, prefix));
+            });
+        }
+        None => {
+            let trailing = document.trailing().as_str().unwrap_or("").to_owned();
+            document.set_trailing(format!("\n{}\n{}", options.marker_end, trailing));
+        }
+    }
+}
+
+/// Sort the keys of `table` and the tables below it, numbering the tables in the new
+/// order from `position`. The keys of the section at `section` are left as they are
+fn sort_table(table: &mut Table, section: &[&str], position: &mut usize) {
+    table.sort_values();
+    for (key, item) in table.iter_mut() {
+        let below = match section.split_first() {
+            Some((first, rest)) if *first == key.get() => Some(rest),
+            _ => None,
+        };
+        match item {
+            Item::Table(table) => {
+                *position += 1;
+                table.set_position(*position);
+                match below {
+                    Some([]) => {}
+                    below => sort_table(table, below.unwrap_or_default(), position),
+                }
+            }
+            Item::ArrayOfTables(array) => {
+                for table in array.iter_mut() {
+                    *position += 1;
+                    table.set_position(*position);
+                    sort_table(table, &[], position);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The table at `path` below `table`, intermediate tables are created as needed.
+/// Every key of the path has been checked to hold a table.
+fn parent_table<'a>(mut table: &'a mut Table, path: &[&str]) -> &'a mut Table {
+    for key in path {
+        let item = table.entry(key).or_insert_with(|| {
+            let mut table = Table::new();
+            table.set_implicit(true);
+            Item::Table(table)
+        });
+        if item.is_inline_table() {
+            let inline = std::mem::take(item);
+            *item = Item::Table(inline.into_table().unwrap());
+        }
+        table = item.as_table_mut().unwrap();
+    }
+    table
+}
+
+/// Remove the markers written by a previous run from a table prefix or the document
+/// trailing, both the configured and the default ones
+fn strip_markers(decor: &str, options: &MergeOptions) -> String {
+    let starts = [options.marker_start.as_str(), DEFAULT_MARKER_START];
+    let mut decor = decor.to_owned();
+    for end in [options.marker_end.as_str(), DEFAULT_MARKER_END] {
+        decor = decor.replace(&format!("\n{}\n", end), "");
+    }
+    decor
+        .split_inclusive('\n')
+        .filter(|line| {
+            !starts.contains(&line.trim_end())
+                && !line.starts_with("# MULTILINE DELIMITER: ")
+                && !is_timestamp_comment(line)
+        })
+        .collect()
+}
+
+/// Whether the table is written with its own header
+fn is_visible(table: &Table) -> bool {
+    let hidden = table.is_implicit() && table.get_values().is_empty();
+    !table.is_dotted() && !hidden
+}
+
+/// Call `f` with every table below `table`, including the tables of arrays of tables
+fn for_each_table(table: &mut Table, f: &mut dyn FnMut(&mut Table)) {
+    for (_, item) in table.iter_mut() {
+        match item {
+            Item::Table(table) => {
+                f(table);
+                for_each_table(table, f);
+            }
+            Item::ArrayOfTables(array) => {
+                for table in array.iter_mut() {
+                    f(table);
+                    for_each_table(table, f);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_merge_env_into_toml_resolve_refs() {
+        let existing = "[server]\nhost = \"0.0.0.0\"\n\n[server.tls]\nport = 8443\n";
+        let path = "src/test_data/refs/refs.env";
+        let patterns = vec![path.to_owned()];
+        let options = MergeOptions {
+            resolve_refs: true,
+            ..Default::default()
+        };
+        let config_content = merge_env_into_toml_with(&patterns, existing, &options).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["BIND"].as_str(), Some("0.0.0.0"));
+        assert_eq!(config["env"]["TLS_PORT"], Value::Integer(8443));
+        assert_eq!(config["env"]["HANDLE"].as_str(), Some("@server.host"));
+
+        // References are kept as written without the flag
+        let config_content = merge_env_into_toml(&patterns, existing).unwrap();
+        let config: Value = toml::from_str(&config_content).unwrap();
+        assert_eq!(config["env"]["BIND"].as_str(), Some("@server.host"));
+
+        let result = merge_env_into_toml_with(&patterns, "[server]\n", &options)
+            .err()
+            .unwrap();
+        assert_eq!(
+            result.to_string(),
+            MergeError::UnresolvedRef("BIND".to_owned(), "server.host".to_owned()).to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge_env_into_toml_sort_tables() {
+        let existing = "zeta = 1\nname = 2\n\n[zoo]\nb = 1\na = 2\n\n[zoo.inner]\nd = 1\nc = 2\n\n[alpha]\nx = 1\n";
+        let patterns = vec!["src/test_data/layered/*.env".to_owned()];
+        let options = MergeOptions {
+            on_duplicate: DuplicatePolicy::LastWins,
+            output_sort: Some(OutputSort::Insertion),
+            ..Default::default()
+        };
+        let merged = merge_env_into_toml_with(&patterns, existing, &options).unwrap();
+        let options = MergeOptions {
+            sort_tables: true,
+            ..options
+        };
+        let sorted = merge_env_into_toml_with(&patterns, &merged, &options).unwrap();
+        let verify =
+            std::fs::read_to_string("src/test_data/sort_tables/sorted_verify.toml").unwrap();
+        assert_eq!(sorted, verify);
+        assert_eq!(
+            merge_env_into_toml_with(&patterns, &sorted, &options).unwrap(),
+            sorted
+        );
+        assert_eq!(
+            merge_env_into_toml_with(&patterns, existing, &options).unwrap(),
+            sorted
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//! YAML output, the section is a mapping in the top-level mapping of the document
+use crate::{
+    datetimes_as_strings, merge_env_table, order_section, EnvVar, MergeError, MergeOptions,
+    MergeReport,
+};
+use anyhow::Result;
+use log::{debug, warn};
+use serde_yaml::{Mapping, Value};
+
+pub(crate) fn merge_existing_yaml(
+    env_vars: &[EnvVar],
+    file_content: &str,
+    options: &MergeOptions,
+) -> Result<(String, toml::Value, MergeReport)> {
+    let invalid = |message: String| MergeError::InvalidExisting("YAML".to_owned(), message);
+    let mut document: Value = match file_content.trim().is_empty() {
+        true => Value::Mapping(Mapping::new()),
+        false => serde_yaml::from_str(file_content).map_err(|e| invalid(e.to_string()))?,
+    };
+    let mut mapping = document
+        .as_mapping_mut()
+        .ok_or_else(|| invalid("the root is not a mapping".to_owned()))?;
+
+    let path = options.section_path();
+    let (name, parents) = path.split_last().unwrap();
+    for (i, key) in parents.iter().enumerate() {
+        mapping = mapping
+            .entry(Value::String(key.to_string()))
+            .or_insert_with(|| Value::Mapping(Mapping::new()))
+            .as_mapping_mut()
+            .ok_or_else(|| MergeError::SectionTypeConflict(path[..=i].join(".")))?;
+    }
+    let section = mapping
+        .entry(Value::String(name.to_string()))
+        .or_insert_with(|| {
+            debug!("Creating new {} mapping", options.section);
+            Value::Mapping(Mapping::new())
+        });
+    if !section.is_mapping() {
+        if !options.force_section {
+            return Err(MergeError::SectionTypeConflict(options.section.clone()).into());
+        }
+        warn!(
+            "Replacing {}: {:?} with a new mapping",
+            options.section, section
+        );
+        *section = Value::Mapping(Mapping::new());
+    }
+    let mut env_table: toml::Value = toml::Value::try_from(&*section)
+        .map_err(|e| invalid(format!("{} can't be merged: {}", options.section, e)))?;
+
+    let report = merge_env_table(env_table.as_table_mut().unwrap(), env_vars, options)?;
+    order_section(&mut env_table, env_vars, options);
+    *section = serde_yaml::to_value(datetimes_as_strings(&env_table))?;
+    Ok((serde_yaml::to_string(&document)?, env_table, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_merge_env_into_yaml() {
+        let patterns = vec!["src/test_data/[0-9].env".to_owned()];
+        let options = MergeOptions {
+            format: OutputFormat::Yaml,
+            ..Default::default()
+        };
+        let existing = std::fs::read_to_string("src/test_data/yaml/old.yaml").unwrap();
+        let config_content = merge_env_into_toml_with(&patterns, &existing, &options).unwrap();
+        let verify_content = std::fs::read_to_string("src/test_data/yaml/old_verify.yaml").unwrap();
+        assert_eq!(config_content, verify_content);
+
+        let result = merge_env_into_toml_with(&patterns, "- env\n", &options)
+            .err()
+            .unwrap();
+        assert!(result
+            .to_string()
+            .starts_with("Existing content is not valid YAML"));
+    }
+}